@@ -1,86 +1,40 @@
 //! bevy sprite animation system
+//!
+//! Animation timing is shared with the server, see [`shared_components::animation`]. This adds
+//! the texture atlas of the sheet and shows the animator's frame on a sprite.
 
 use bevy::asset::{AssetLoader, AssetPath, BoxedFuture, Error, LoadContext, LoadedAsset};
 use bevy::prelude::*;
 use bevy::reflect::TypeUuid;
 use bevy::sprite::Rect;
-use bevy::utils::HashMap;
+use shared_components::animation::{AnimationSet, Animator};
 use std::sync::Arc;
-use std::time::Duration;
 
 #[derive(Debug, TypeUuid)]
 #[uuid = "dfcaf828-ebd1-4bc1-acb4-ab14db715331"]
 pub struct AnimatorArchetype {
-    animations: Arc<HashMap<String, Animation>>,
-    frame_times: Arc<Vec<Duration>>,
+    animations: Arc<AnimationSet>,
     texture_atlas_handle: Option<Handle<TextureAtlas>>,
 }
 
 impl AnimatorArchetype {
-    pub fn from_aseprite(data: &aseprite::SpritesheetData) -> Self {
-        let frame_tags = data
-            .meta
-            .frame_tags
-            .as_ref()
-            .expect("missing frameTags from spritesheet");
-
-        let (loops, others): (Vec<_>, Vec<_>) =
-            frame_tags.iter().partition(|tag| &tag.name == "loop");
-
-        let mut animations = HashMap::with_capacity(others.len());
-
-        for animation in others {
-            // find loop for this animation
-            let (loop_start, loop_end) = loops
-                .iter()
-                .find(|l| l.from >= animation.from && l.to <= animation.to)
-                .map(|l| (l.from, l.to))
-                .unwrap_or((animation.from, animation.to));
-
-            animations.insert(
-                animation.name.clone(),
-                Animation {
-                    start_index: animation.from as usize,
-                    end_index: animation.to as usize,
-                    loop_start: loop_start as usize,
-                    loop_end: loop_end as usize,
-                },
-            );
-        }
-
-        let frame_times = data
-            .frames
-            .iter()
-            .map(|frame| Duration::from_millis(frame.duration as u64))
-            .collect();
-
-        AnimatorArchetype {
-            animations: Arc::new(animations),
-            frame_times: Arc::new(frame_times),
+    pub fn from_aseprite(data: &aseprite::SpritesheetData) -> Result<Self, Error> {
+        Ok(AnimatorArchetype {
+            animations: Arc::new(AnimationSet::from_aseprite(data)?),
             texture_atlas_handle: None,
-        }
+        })
     }
 
+    #[allow(dead_code)]
     pub fn set_texture_handle(&mut self, texture_atlas_handle: Handle<TextureAtlas>) {
         self.texture_atlas_handle = Some(texture_atlas_handle);
     }
 
     pub fn new_instance(&self) -> Animator {
-        let idle = self
-            .animations
-            .get("idle")
-            .expect("animation set should have idle animation")
-            .clone();
-        Animator {
-            animations: Arc::clone(&self.animations),
-            frame_times: Arc::clone(&self.frame_times),
-            current_frame: idle.start_index,
-            animation_timer: Timer::new(self.frame_times[idle.start_index].clone(), false),
-            current_animation: idle,
-            next_animation: None,
-        }
+        Animator::new(Arc::clone(&self.animations))
     }
 
+    #[allow(dead_code)]
     pub fn texture_handle(&self) -> Option<&Handle<TextureAtlas>> {
         self.texture_atlas_handle.as_ref()
     }
@@ -97,7 +51,7 @@ impl AssetLoader for AsepriteAnimatorLoader {
     ) -> BoxedFuture<'a, anyhow::Result<(), Error>> {
         Box::pin(async move {
             let data: aseprite::SpritesheetData = serde_json::from_slice(bytes)?;
-            let mut archetype = AnimatorArchetype::from_aseprite(&data);
+            let mut archetype = AnimatorArchetype::from_aseprite(&data)?;
             debug!("loaded animator from aseprite json");
 
             let maybe_texture = if let Some(img_path) = data.meta.image {
@@ -147,18 +101,20 @@ impl AssetLoader for AsepriteAnimatorLoader {
     }
 }
 
+type UnassignedAnimator<'a> = (
+    Entity,
+    &'a Handle<AnimatorArchetype>,
+    Option<&'a Transform>,
+    Option<&'a GlobalTransform>,
+);
+
 pub fn assign_animator(
     mut commands: Commands,
-    unassigned: Query<(
-        Entity,
-        &Handle<AnimatorArchetype>,
-        Option<&Transform>,
-        Option<&GlobalTransform>,
-    )>,
+    unassigned: Query<UnassignedAnimator>,
     archetypes: Res<Assets<AnimatorArchetype>>,
 ) {
     for (entity, handle, transform, global_transform) in unassigned.iter() {
-        if let Some(archetype) = archetypes.get(&handle) {
+        if let Some(archetype) = archetypes.get(handle) {
             let mut entity_commands = commands.entity(entity);
             entity_commands.remove::<Handle<AnimatorArchetype>>();
             entity_commands.insert(archetype.new_instance());
@@ -182,68 +138,8 @@ pub fn animate_sprite(
     time: Res<Time>,
 ) {
     for (mut animator, mut sprite) in animators.iter_mut() {
-        if animator.animation_timer.tick(time.delta()).finished() {
-            animator.current_frame += 1;
-
-            // if we have a next animation waiting, continue until end
-            if animator.next_animation.is_some() {
-                if animator.current_frame > animator.current_animation.end_index {
-                    animator.current_animation = animator.next_animation.take().unwrap();
-                    animator.current_frame = animator.current_animation.start_index;
-                }
-            } else {
-                if animator.current_frame > animator.current_animation.loop_end {
-                    animator.current_frame = animator.current_animation.loop_start;
-                }
-            }
-
-            // reset the timer as it just fired
-            let new_duration = animator.frame_times[animator.current_frame].clone();
-            animator.animation_timer.set_duration(new_duration);
-            animator.animation_timer.reset();
-
-            // set the sprite
-            sprite.index = animator.current_frame;
-        }
-    }
-}
-
-#[derive(Debug, Clone, Default)]
-pub struct Animation {
-    start_index: usize,
-    end_index: usize,
-    loop_start: usize,
-    loop_end: usize,
-}
-
-#[derive(Default, Debug, Clone, Component)]
-pub struct Animator {
-    animations: Arc<HashMap<String, Animation>>,
-    frame_times: Arc<Vec<Duration>>,
-    current_animation: Animation,
-    animation_timer: Timer,
-    current_frame: usize,
-    next_animation: Option<Animation>,
-}
-
-impl Animator {
-    pub fn play(&mut self, name: &str) {
-        if let Some(anim) = self.animations.get(name).cloned() {
-            self.next_animation = Some(anim);
-        } else {
-            debug!("attempted to play unknown animation '{}'", name);
-        }
-    }
-
-    pub fn set_animation(&mut self, name: &str) {
-        if let Some(anim) = self.animations.get(name).cloned() {
-            self.current_animation = anim;
-            self.current_frame = self.current_animation.start_index;
-            self.animation_timer
-                .set_duration(self.frame_times[self.current_frame].clone());
-            self.animation_timer.reset();
-        } else {
-            debug!("attempted to set unknown animation '{}'", name);
+        if animator.tick(time.delta()) {
+            sprite.index = animator.current_frame();
         }
     }
 }
@@ -0,0 +1,210 @@
+//! The chat box
+//!
+//! Recent chat lines and the line being typed are drawn in the bottom left corner. Enter
+//! starts typing and sends the line, Escape drops it. Lines starting with `/` are commands the
+//! server runs, `/help` lists them. Answers to room commands show up as server notices.
+
+use crate::rooms::RoomNotice;
+use bevy::prelude::*;
+use shared_components::chat::{ChatChannel, MAX_MESSAGE_LENGTH};
+use shared_components::{ChatMessage, SendChat};
+use std::collections::VecDeque;
+
+const FONT: &str = "fonts/DejaVuSansMono.ttf";
+const FONT_SIZE: f32 = 16.;
+/// Lines shown in the chat box
+const VISIBLE_LINES: usize = 10;
+
+/// Received lines, the oldest first
+#[derive(Default)]
+struct ChatLines(VecDeque<(ChatChannel, String)>);
+
+/// The line being typed, if the player is typing
+#[derive(Default)]
+struct ChatInput(Option<String>);
+
+#[derive(Component)]
+struct ChatText;
+
+fn spawn_chat_box(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font = asset_server.load(FONT);
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(8.),
+                    bottom: Val::Px(8.),
+                    ..default()
+                },
+                padding: UiRect::all(Val::Px(4.)),
+                ..default()
+            },
+            color: Color::rgba(0., 0., 0., 0.5).into(),
+            ..default()
+        })
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle {
+                    // a section per line, so each can have the color of its channel, then the
+                    // input line
+                    text: Text::from_sections((0..=VISIBLE_LINES).map(|_| {
+                        TextSection::from_style(TextStyle {
+                            font: font.clone(),
+                            font_size: FONT_SIZE,
+                            color: Color::WHITE,
+                        })
+                    })),
+                    style: Style {
+                        max_size: Size::new(Val::Px(480.), Val::Undefined),
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(ChatText);
+        });
+}
+
+impl ChatLines {
+    fn push(&mut self, channel: ChatChannel, line: String) {
+        self.0.push_back((channel, line));
+        while self.0.len() > VISIBLE_LINES {
+            self.0.pop_front();
+        }
+    }
+}
+
+fn receive_chat(mut messages: EventReader<ChatMessage>, mut lines: ResMut<ChatLines>) {
+    for message in messages.iter() {
+        let channel = match ChatChannel::from_u8(message.channel) {
+            Some(channel) => channel,
+            None => {
+                debug!("chat message on unknown channel {}", message.channel);
+                continue;
+            }
+        };
+        let line = match channel {
+            ChatChannel::Global => format!("{}: {}", message.from, message.text),
+            ChatChannel::Nearby => format!("(nearby) {}: {}", message.from, message.text),
+            ChatChannel::Whisper => format!("(whisper) {}: {}", message.from, message.text),
+            ChatChannel::System => format!("* {}", message.text),
+        };
+        lines.push(channel, line);
+    }
+}
+
+fn show_room_notices(mut notices: EventReader<RoomNotice>, mut lines: ResMut<ChatLines>) {
+    for notice in notices.iter() {
+        match notice {
+            RoomNotice::List(rooms) => {
+                for room in rooms {
+                    let line = format!("* room {}: {} ({} in)", room.id, room.name, room.players);
+                    lines.push(ChatChannel::System, line);
+                }
+            }
+            RoomNotice::Joined { room, name } => {
+                let line = format!("* you are in room {}: {}", room, name);
+                lines.push(ChatChannel::System, line);
+            }
+            RoomNotice::Refused(reason) => lines.push(ChatChannel::System, format!("* {}", reason)),
+        }
+    }
+}
+
+fn type_chat(
+    keys: Res<Input<KeyCode>>,
+    mut characters: EventReader<ReceivedCharacter>,
+    mut input: ResMut<ChatInput>,
+    mut sends: EventWriter<SendChat>,
+) {
+    // only borrowed mutably while typing, so `show_chat` doesn't redraw every frame
+    if input.0.is_none() {
+        characters.clear();
+        if keys.just_pressed(KeyCode::Return) {
+            input.0 = Some(String::new());
+        }
+        return;
+    }
+    let line = input.0.as_mut().unwrap();
+
+    // control characters also arrive for Enter, Escape and Backspace, the keys handle those
+    for c in characters
+        .iter()
+        .map(|c| c.char)
+        .filter(|c| !c.is_control())
+    {
+        if line.chars().count() < MAX_MESSAGE_LENGTH {
+            line.push(c);
+        }
+    }
+    if keys.just_pressed(KeyCode::Back) {
+        line.pop();
+    }
+    if keys.just_pressed(KeyCode::Return) {
+        if !line.trim().is_empty() {
+            sends.send(SendChat {
+                text: std::mem::take(line),
+            });
+        }
+        input.0 = None;
+    } else if keys.just_pressed(KeyCode::Escape) {
+        input.0 = None;
+    }
+}
+
+fn channel_color(channel: ChatChannel) -> Color {
+    match channel {
+        ChatChannel::Global => Color::WHITE,
+        ChatChannel::Nearby => Color::rgb(0.6, 1., 0.6),
+        ChatChannel::Whisper => Color::rgb(1., 0.6, 1.),
+        ChatChannel::System => Color::rgb(1., 1., 0.5),
+    }
+}
+
+fn show_chat(
+    lines: Res<ChatLines>,
+    input: Res<ChatInput>,
+    mut texts: Query<&mut Text, With<ChatText>>,
+) {
+    if !lines.is_changed() && !input.is_changed() {
+        return;
+    }
+
+    for mut text in texts.iter_mut() {
+        let (log, input_line) = text.sections.split_at_mut(VISIBLE_LINES);
+        // the latest line goes right above the input line
+        let empty = VISIBLE_LINES - lines.0.len();
+        for (i, section) in log.iter_mut().enumerate() {
+            match i.checked_sub(empty).and_then(|i| lines.0.get(i)) {
+                Some((channel, line)) => {
+                    section.value = format!("{}\n", line);
+                    section.style.color = channel_color(*channel);
+                }
+                None => section.value.clear(),
+            }
+        }
+        input_line[0].value = match &input.0 {
+            Some(line) => format!("> {}_", line),
+            None => "press Enter to chat".into(),
+        };
+    }
+}
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatLines>()
+            .init_resource::<ChatInput>()
+            .add_startup_system(spawn_chat_box)
+            .add_system(receive_chat)
+            .add_system(show_room_notices)
+            .add_system(type_chat)
+            .add_system(
+                show_chat
+                    .after(receive_chat)
+                    .after(show_room_notices)
+                    .after(type_chat),
+            );
+    }
+}
@@ -0,0 +1,102 @@
+//! Estimate of the server tick on the client
+//!
+//! The client pings the server every [`PING_INTERVAL`] seconds. Each pong carries the tick the
+//! server was at when it got the ping, which is assumed to be half a round trip ago. The offset
+//! between the local clock and the server tick is smoothed over several pongs, so a single slow
+//! round trip doesn't make the estimate jump.
+
+use bevy::prelude::*;
+use futures::channel::mpsc::Sender;
+use messages::{PlayerMessage, Tick, TICK_RATE};
+
+/// Seconds between two pings
+const PING_INTERVAL: f32 = 1.;
+/// How much of the difference to a new estimate is applied per pong
+const SMOOTHING: f64 = 0.1;
+
+#[derive(Default, Debug)]
+pub struct ServerClock {
+    /// Server tick minus local time in ticks, once the first pong arrived
+    offset: Option<f64>,
+    /// Smoothed round trip time in seconds
+    rtt: f64,
+    /// Tick of the latest batch of messages from the server
+    received_tick: Option<Tick>,
+}
+
+impl ServerClock {
+    /// Estimated server tick at local time `now`, with a fractional part
+    pub fn tick_at(&self, now: f64) -> Option<f64> {
+        self.offset.map(|offset| now * TICK_RATE + offset)
+    }
+
+    pub fn rtt(&self) -> f64 {
+        self.rtt
+    }
+
+    pub fn received_tick(&self) -> Option<Tick> {
+        self.received_tick
+    }
+
+    pub fn receive_tick(&mut self, tick: Tick) {
+        self.received_tick = Some(tick);
+    }
+
+    pub fn receive_pong(&mut self, now: f64, sent_at: f64, tick: Tick) {
+        let rtt = (now - sent_at).max(0.);
+        let offset = tick as f64 + rtt / 2. * TICK_RATE - now * TICK_RATE;
+
+        match self.offset {
+            Some(ref mut old) => {
+                *old += (offset - *old) * SMOOTHING;
+                self.rtt += (rtt - self.rtt) * SMOOTHING;
+            }
+            None => {
+                self.offset = Some(offset);
+                self.rtt = rtt;
+            }
+        }
+    }
+}
+
+#[derive(Deref, DerefMut)]
+struct PingTimer(Timer);
+
+fn send_pings(
+    time: Res<Time>,
+    mut timer: ResMut<PingTimer>,
+    clock: Res<ServerClock>,
+    mut sender: ResMut<Sender<PlayerMessage>>,
+) {
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let now = time.seconds_since_startup();
+    if let (Some(estimate), Some(received)) = (clock.tick_at(now), clock.received_tick()) {
+        debug!(
+            "server tick estimate {:.1}, latest received {} ({:.1} behind), rtt {:.0}ms",
+            estimate,
+            received,
+            estimate - received as f64,
+            clock.rtt() * 1000.
+        );
+    }
+
+    if sender
+        .try_send(PlayerMessage::Ping { sent_at: now })
+        .is_err()
+    {
+        warn!("failed to queue a ping for the server");
+    }
+}
+
+pub struct ClockPlugin;
+
+impl Plugin for ClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerClock>()
+            .insert_resource(PingTimer(Timer::from_seconds(PING_INTERVAL, true)))
+            .add_system(send_pings);
+    }
+}
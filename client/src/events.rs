@@ -0,0 +1,114 @@
+//! Networked events to and from the server
+//!
+//! Client events are plain bevy events that get forwarded to the server, server events are
+//! decoded from [`ServerMessage::Event`]s and sent as bevy events. Each event type has to be
+//! registered with [`NetworkEventAppExt`].
+//!
+//! [`ServerMessage::Event`]: messages::ServerMessage::Event
+
+use crate::HandleServerMessages;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use futures::channel::mpsc::Sender;
+use messages::{NetworkEntityMap, PlayerMessage};
+use shared_components::entity_mapping::{self, map_entities, wire_entity};
+use shared_components::NetworkEvent;
+
+/// Raw events received from the server this frame, by kind id
+#[derive(Default)]
+pub struct ReceivedEvents(HashMap<u16, Vec<Vec<u8>>>);
+
+impl ReceivedEvents {
+    pub fn push(&mut self, kind: u16, data: Vec<u8>) {
+        self.0.entry(kind).or_default().push(data);
+    }
+}
+
+fn send_client_events<E: NetworkEvent>(
+    mut events: EventReader<E>,
+    network_entities: Res<NetworkEntityMap>,
+    mut sender: ResMut<Sender<PlayerMessage>>,
+) {
+    for event in events.iter() {
+        let mut event = event.clone();
+        if let Err(e) = map_entities(&mut event, &mut |e| {
+            network_entities.get_network(&e).map(wire_entity)
+        }) {
+            warn!("not sending event {}: {}", E::KIND_ID, e);
+            continue;
+        }
+
+        let message = PlayerMessage::Event {
+            kind: E::KIND_ID,
+            data: postcard::to_allocvec(&event).unwrap(),
+        };
+        if sender.try_send(message).is_err() {
+            warn!("failed to queue event {} for the server", E::KIND_ID);
+        }
+    }
+}
+
+fn receive_server_events<E: NetworkEvent>(
+    mut received: ResMut<ReceivedEvents>,
+    network_entities: Res<NetworkEntityMap>,
+    mut events: EventWriter<E>,
+) {
+    let pending = match received.0.remove(&E::KIND_ID) {
+        Some(pending) => pending,
+        None => return,
+    };
+
+    for data in pending {
+        let mut event: E = match postcard::from_bytes(&data) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("failed to decode event {}: {}", E::KIND_ID, e);
+                continue;
+            }
+        };
+
+        if let Err(e) = map_entities(&mut event, &mut |wire| {
+            network_entities.get_local(&entity_mapping::network_entity(wire))
+        }) {
+            warn!("dropping event {}: {}", E::KIND_ID, e);
+            continue;
+        }
+
+        events.send(event);
+    }
+}
+
+/// Drops events nobody registered, so they don't pile up
+fn discard_unknown_events(mut received: ResMut<ReceivedEvents>) {
+    for (kind, pending) in received.0.drain() {
+        debug!("dropping {} events of unknown kind {}", pending.len(), kind);
+    }
+}
+
+pub trait NetworkEventAppExt {
+    /// Registers an event that is sent to the server
+    fn add_client_event<E: NetworkEvent>(&mut self) -> &mut Self;
+    /// Registers an event that is received from the server
+    fn add_server_event<E: NetworkEvent>(&mut self) -> &mut Self;
+}
+
+impl NetworkEventAppExt for App {
+    fn add_client_event<E: NetworkEvent>(&mut self) -> &mut Self {
+        self.add_event::<E>()
+            .add_system_to_stage(CoreStage::PostUpdate, send_client_events::<E>)
+    }
+
+    fn add_server_event<E: NetworkEvent>(&mut self) -> &mut Self {
+        self.add_event::<E>()
+            .add_system(receive_server_events::<E>.after(HandleServerMessages))
+    }
+}
+
+pub struct NetworkEventPlugin;
+
+impl Plugin for NetworkEventPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReceivedEvents>()
+            .add_system_to_stage(CoreStage::Last, discard_unknown_events);
+    }
+}
@@ -0,0 +1,166 @@
+//! Snapshot interpolation of replicated transforms
+//!
+//! Every [`NTransform`] the server sends is buffered along with the tick of the batch it came
+//! in. Entities are rendered [`InterpolationSettings::delay`] behind the estimated server time,
+//! between the two buffered states around that point, so updates arriving at 30 Hz with some
+//! jitter still move smoothly. When the buffer runs dry the entity keeps going at its last
+//! velocity for at most [`InterpolationSettings::max_extrapolation`], then holds still.
+
+use crate::clock::ServerClock;
+use crate::PlayerControlled;
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+use messages::{Tick, TICK_RATE};
+use shared_components::NTransform;
+use std::collections::VecDeque;
+
+/// Buffered states beyond this are dropped, oldest first
+const MAX_SNAPSHOTS: usize = 32;
+
+pub struct InterpolationSettings {
+    /// How far behind the estimated server time entities are rendered, in seconds
+    pub delay: f64,
+    /// How long an entity is extrapolated past its latest state, in seconds
+    pub max_extrapolation: f64,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        InterpolationSettings {
+            delay: 0.1,
+            max_extrapolation: 0.25,
+        }
+    }
+}
+
+impl InterpolationSettings {
+    /// Server tick remote entities are shown at, at local time `now`
+    pub fn render_tick(&self, clock: &ServerClock, now: f64) -> Option<f64> {
+        clock.tick_at(now).map(|tick| tick - self.delay * TICK_RATE)
+    }
+}
+
+/// Received transforms of an entity, oldest first
+#[derive(Component, Default)]
+pub struct TransformSnapshots(VecDeque<(Tick, NTransform)>);
+
+impl TransformSnapshots {
+    /// The newest state, and the tick the server sent it at
+    pub fn latest(&self) -> Option<(Tick, &NTransform)> {
+        self.0.back().map(|(tick, transform)| (*tick, transform))
+    }
+
+    fn push(&mut self, tick: Tick, transform: NTransform) {
+        match self.0.back() {
+            Some((last, _)) if *last > tick => return,
+            // a resend within the same batch replaces the earlier state
+            Some((last, _)) if *last == tick => {
+                self.0.pop_back();
+            }
+            _ => {}
+        }
+        self.0.push_back((tick, transform));
+        if self.0.len() > MAX_SNAPSHOTS {
+            self.0.pop_front();
+        }
+    }
+
+    /// The state at a (fractional) tick
+    fn sample(&self, tick: f64, max_extrapolation: f64) -> Option<NTransform> {
+        let after = self.0.iter().position(|(t, _)| *t as f64 > tick);
+        match after {
+            // before the first state, hold it
+            Some(0) => self.0.front().map(|(_, transform)| transform.clone()),
+            Some(i) => {
+                let (from_tick, from) = &self.0[i - 1];
+                let (to_tick, to) = &self.0[i];
+                let t = (tick - *from_tick as f64) / (*to_tick - *from_tick) as f64;
+                Some(lerp(from, to, t as f32))
+            }
+            // past the last state, keep going for a bit
+            None => {
+                let mut newest = self.0.iter().rev();
+                let (to_tick, to) = newest.next()?;
+                let (from_tick, from) = match newest.next() {
+                    Some(previous) => previous,
+                    None => return Some(to.clone()),
+                };
+                let ahead = (tick - *to_tick as f64).min(max_extrapolation);
+                let t = 1. + ahead / (*to_tick - *from_tick) as f64;
+                Some(lerp(from, to, t as f32))
+            }
+        }
+    }
+
+    /// Drops states that are no longer needed to render at `tick`
+    fn discard_before(&mut self, tick: f64) {
+        while self.0.len() > 2 && self.0[1].0 as f64 <= tick {
+            self.0.pop_front();
+        }
+    }
+}
+
+fn lerp(from: &NTransform, to: &NTransform, t: f32) -> NTransform {
+    NTransform {
+        translation: from.translation.lerp(to.translation, t),
+        scale: from.scale.lerp(to.scale, t),
+    }
+}
+
+/// Buffers the [`NTransform`] `entity` just received, which the server sent at `tick`
+///
+/// Called as each message is applied rather than once per frame, so the states of several
+/// batches that arrive in the same frame each keep the tick of their own batch.
+pub fn buffer_snapshot(world: &mut World, entity: Entity, tick: Tick) {
+    let n_transform = match world.get::<NTransform>(entity) {
+        Some(n_transform) => n_transform.clone(),
+        None => return,
+    };
+    let mut entity = world.entity_mut(entity);
+    match entity.get_mut::<TransformSnapshots>() {
+        Some(mut snapshots) => snapshots.push(tick, n_transform),
+        None => {
+            let transform = n_transform.as_transform();
+            let mut snapshots = TransformSnapshots::default();
+            snapshots.push(tick, n_transform);
+            entity
+                .insert(snapshots)
+                .insert_bundle(TransformBundle::from_transform(transform));
+        }
+    }
+}
+
+fn interpolate_transforms(
+    time: Res<Time>,
+    clock: Res<ServerClock>,
+    settings: Res<InterpolationSettings>,
+    // the controlled entity is predicted instead
+    mut entities: Query<(&mut TransformSnapshots, &mut Transform), Without<PlayerControlled>>,
+) {
+    let render_tick = match settings.render_tick(&clock, time.seconds_since_startup()) {
+        Some(tick) => tick,
+        None => return,
+    };
+    let max_extrapolation = settings.max_extrapolation * TICK_RATE;
+
+    for (mut snapshots, mut transform) in entities.iter_mut() {
+        if let Some(n_transform) = snapshots.sample(render_tick, max_extrapolation) {
+            let sampled = n_transform.as_transform();
+            transform.translation = sampled.translation;
+            transform.scale = sampled.scale;
+        }
+        snapshots.discard_before(render_tick);
+    }
+}
+
+pub struct InterpolationPlugin;
+
+impl Plugin for InterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InterpolationSettings>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                interpolate_transforms.before(TransformSystem::TransformPropagate),
+            );
+    }
+}
@@ -1,40 +1,69 @@
 //! Multiplayer webRTC test with Bevy
 
 mod animator;
-
-use crate::animator::AnimatorArchetype;
+mod chat;
+mod clock;
+mod events;
+mod interpolation;
+mod prediction;
+mod resources;
+mod rooms;
+mod tilemap;
+
+use animator::AnimatorArchetype;
 use bevy::log::LogSettings;
 use bevy::reflect::TypeRegistry;
 use bevy::render::camera::RenderTarget;
 use bevy::render::renderer::RenderDevice;
+use bevy::sprite::MaterialMesh2dBundle;
 use bevy::tasks::IoTaskPool;
-use bevy::utils::HashMap;
+use bevy::utils::{Duration, HashMap};
 use bevy::{prelude::*, render::texture::ImageSettings};
+use chat::ChatPlugin;
+use clock::{ClockPlugin, ServerClock};
+use events::{NetworkEventAppExt, NetworkEventPlugin, ReceivedEvents};
 use futures::channel::mpsc::Receiver;
 use futures::prelude::*;
-use messages::{NetworkEntity, ServerMessage};
-use shared_components::{NSprite, NTransform};
+use interpolation::{InterpolationPlugin, InterpolationSettings};
+use messages::{NetworkEntity, NetworkEntityMap, ServerMessage, Tick, TICK_RATE};
+use prediction::{InputHistory, PredictionPlugin};
+use resources::{NetworkResourceAppExt, NetworkResourcePlugin, ReceivedResources};
+use rooms::{RoomNotice, RoomPlugin};
+use shared_components::animation::Animator;
+use shared_components::entity_mapping::{self, map_entities};
+use shared_components::movement::{MoveTarget, MovementSettings};
+use shared_components::{
+    ChatMessage, NAnimation, NCollider, NControlled, NObstacle, NParent, NTransform, PlayEffect,
+    SendChat, Tilemap, UseAbility, WorldSettings,
+};
 use std::any::TypeId;
+use tilemap::TilemapPlugin;
 use ws_stream_wasm::*;
 
-#[derive(Component)]
-struct MoveTarget(Vec3);
 #[derive(Component)]
 struct PlayerControlled;
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct HandleServerMessages;
+
 fn main() {
     // When building for WASM, print panics to the browser console
     #[cfg(target_arch = "wasm32")]
     console_error_panic_hook::set_once();
 
     App::new()
-        .register_type::<NSprite>()
         .register_type::<NTransform>()
+        .register_type::<NParent>()
+        .register_type::<NControlled>()
+        .register_type::<NAnimation>()
+        .register_type::<NCollider>()
+        .register_type::<NObstacle>()
         .insert_resource(WindowDescriptor {
             present_mode: bevy::window::PresentMode::AutoVsync,
             ..Default::default()
         })
         .insert_resource(shared_components::kind_to_type_id_mappings())
+        .init_resource::<NetworkEntityMap>()
         .insert_resource(LogSettings {
             filter: "warn,client=debug".into(),
             level: bevy::log::Level::DEBUG,
@@ -42,15 +71,34 @@ fn main() {
         .insert_resource(ImageSettings::default_nearest()) // prevents blurry sprites
         .add_plugins(DefaultPlugins)
         .add_plugin(animator::AnimatorPlugin)
+        .add_plugin(NetworkEventPlugin)
+        .add_client_event::<UseAbility>()
+        .add_server_event::<PlayEffect>()
+        .add_client_event::<SendChat>()
+        .add_server_event::<ChatMessage>()
+        .add_plugin(ChatPlugin)
+        .add_plugin(RoomPlugin)
+        .add_plugin(NetworkResourcePlugin)
+        .add_plugin(ClockPlugin)
+        .add_plugin(InterpolationPlugin)
+        .init_resource::<MovementSettings>()
+        .add_plugin(PredictionPlugin)
+        .add_networked_resource::<WorldSettings>()
+        .add_networked_resource::<Tilemap>()
+        .add_plugin(TilemapPlugin)
         .add_startup_system(setup)
         .add_startup_system(print_renderer_limits)
-        // .add_startup_system(spawn_websocket_client)
-        // .add_system(handle_server_message)
-        // .add_system(translate_sprites)
-        // .add_system(translate_transfom)
-        // .add_system(animate_sprite)
-        // .add_system(player_input)
-        // .add_system(move_entities)
+        .add_startup_system(spawn_websocket_client)
+        .add_system(handle_server_message.label(HandleServerMessages))
+        .add_system(apply_parents)
+        .add_system(take_control)
+        .add_system(add_animators)
+        .add_system(play_animations)
+        .add_system(show_obstacles)
+        .add_system(play_effects)
+        .add_system(show_world_settings)
+        .add_system_to_stage(CoreStage::Last, forget_despawned_entities)
+        .add_system(player_input)
         .run();
 }
 
@@ -58,115 +106,328 @@ fn print_renderer_limits(device: Res<RenderDevice>) {
     info!("render device limits: {:#?}", device.limits());
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_server_message(
     mut commands: Commands,
-    entity_finder: Query<(Entity, &NetworkEntity)>,
     type_mappings: Res<(HashMap<u16, TypeId>, HashMap<TypeId, u16>)>,
     mut receiver: ResMut<Receiver<ServerMessage>>,
-    mut entity_lookup: Local<HashMap<NetworkEntity, Entity>>,
+    mut network_entities: ResMut<NetworkEntityMap>,
+    mut received_events: ResMut<ReceivedEvents>,
+    mut received_resources: ResMut<ReceivedResources>,
+    mut clock: ResMut<ServerClock>,
+    mut input_history: ResMut<InputHistory>,
+    mut room_notices: EventWriter<RoomNotice>,
+    time: Res<Time>,
 ) {
-    let mut find_entity = |network_entity: &NetworkEntity, commands: &mut Commands| {
-        if let Some(entity) = entity_lookup.get(network_entity) {
-            entity.clone()
-        } else {
-            let entity = if let Some((entity, _)) =
-                entity_finder.iter().find(|(_, ne)| **ne == *network_entity)
-            {
-                entity.clone()
-            } else {
-                debug!("spawned a new entity: {:?}", &network_entity);
-                commands.spawn_bundle((*network_entity,)).id()
-            };
-            entity_lookup.insert(*network_entity, entity);
-            entity
-        }
-    };
-
+    // every batch starts with its tick, and what follows was sent at that tick
+    let mut batch_tick = clock.received_tick();
     while let Ok(Some(msg)) = receiver.try_next() {
         match msg {
+            ServerMessage::Tick { tick } => {
+                clock.receive_tick(tick);
+                batch_tick = Some(tick);
+            }
+            ServerMessage::InputAck { sequence } => match batch_tick {
+                Some(tick) => input_history.ack(sequence, tick),
+                None => warn!("input ack {} arrived before any tick", sequence),
+            },
+            ServerMessage::Pong { sent_at, tick } => {
+                clock.receive_pong(time.seconds_since_startup(), sent_at, tick)
+            }
             ServerMessage::Welcome { .. } => {}
             ServerMessage::Refresh { .. } => {}
-            ServerMessage::ComponentAdded { .. } => {}
-            ServerMessage::ComponentChanged {
+            ServerMessage::EntitySpawned { entity, components } => {
+                let e = find_or_spawn(&entity, &mut network_entities, &mut commands);
+                let components = components
+                    .into_iter()
+                    .filter_map(|(component, data)| match type_mappings.0.get(&component) {
+                        Some(type_id) => Some((*type_id, data)),
+                        None => {
+                            warn!("skipping unknown component kind {}", component);
+                            None
+                        }
+                    })
+                    .collect();
+
+                commands.add(move |world: &mut World| {
+                    insert_components(world, e, components, batch_tick)
+                });
+            }
+            ServerMessage::ComponentAdded {
+                entity,
+                component,
+                data,
+            }
+            | ServerMessage::ComponentChanged {
                 entity,
                 component,
                 data,
             } => {
-                let e = find_entity(&entity, &mut commands);
-                let type_id = type_mappings.0.get(&component).unwrap().clone();
+                let type_id = match type_mappings.0.get(&component) {
+                    Some(type_id) => *type_id,
+                    None => {
+                        warn!("skipping unknown component kind {}", component);
+                        continue;
+                    }
+                };
+                let e = find_or_spawn(&entity, &mut network_entities, &mut commands);
 
                 commands.add(move |world: &mut World| {
-                    world.resource_scope(|world, register: Mut<TypeRegistry>| {
-                        let read_registry = register.read();
-                        //let deser = ReflectDeserializer::new(&*read_registry);
+                    insert_components(world, e, vec![(type_id, data)], batch_tick)
+                });
+            }
+            ServerMessage::ComponentRemoved { entity, component } => {
+                let type_id = match type_mappings.0.get(&component) {
+                    Some(type_id) => *type_id,
+                    None => {
+                        warn!("skipping unknown component kind {}", component);
+                        continue;
+                    }
+                };
+                if let Some(e) = network_entities.get_local(&entity) {
+                    commands.add(move |world: &mut World| remove_component(world, e, type_id));
+                }
+            }
+            ServerMessage::EntityDespawned { entity } => {
+                if let Some(e) = network_entities.remove_network(&entity) {
+                    debug!("despawned entity: {:?}", &entity);
+                    commands.entity(e).despawn_recursive();
+                }
+            }
+            ServerMessage::ResourceChanged { kind, data } => received_resources.push(kind, data),
+            ServerMessage::Event { kind, data } => received_events.push(kind, data),
+            ServerMessage::RoomList { rooms } => room_notices.send(RoomNotice::List(rooms)),
+            ServerMessage::RoomJoined { room, name } => {
+                room_notices.send(RoomNotice::Joined { room, name })
+            }
+            ServerMessage::RoomRefused { reason } => room_notices.send(RoomNotice::Refused(reason)),
+        }
+    }
+}
 
-                        let registration = read_registry
-                            .get(type_id)
-                            .expect("invalid component received");
+/// Decodes networked components and inserts them into an entity, all in one go
+///
+/// Transforms are also buffered for interpolation, along with `tick`, the tick of the batch
+/// they came in.
+fn insert_components(
+    world: &mut World,
+    entity: Entity,
+    components: Vec<(TypeId, Vec<u8>)>,
+    tick: Option<Tick>,
+) {
+    world.resource_scope(|world, register: Mut<TypeRegistry>| {
+        let read_registry = register.read();
+        //let deser = ReflectDeserializer::new(&*read_registry);
+
+        for (type_id, data) in components {
+            let registration = match read_registry.get(type_id) {
+                Some(registration) => registration,
+                None => {
+                    warn!("skipping unregistered component {:?}", type_id);
+                    continue;
+                }
+            };
+
+            let deser = registration.data::<ReflectDeserialize>().unwrap();
 
-                        let deser = registration.data::<ReflectDeserialize>().unwrap();
+            // let mut deserializer = rmp_serde::Deserializer::from_read_ref(&data);
+            let mut deserializer = postcard::Deserializer::from_bytes(&data);
 
-                        // let mut deserializer = rmp_serde::Deserializer::from_read_ref(&data);
-                        let mut deserializer = postcard::Deserializer::from_bytes(&data);
+            let mut component_de = match deser.deserialize(&mut deserializer) {
+                Ok(component) => component,
+                Err(e) => {
+                    warn!(
+                        "failed to decode component {}: {}",
+                        registration.short_name(),
+                        e
+                    );
+                    continue;
+                }
+            };
 
-                        let component_de = deser
-                            .deserialize(&mut deserializer)
-                            .expect("failed to deserialize component");
+            map_to_local(world, component_de.as_mut());
 
-                        registration.data::<ReflectComponent>().unwrap().insert(
-                            world,
-                            e,
-                            component_de.as_ref(),
-                        )
-                    });
-                });
+            registration.data::<ReflectComponent>().unwrap().insert(
+                world,
+                entity,
+                component_de.as_ref(),
+            );
+
+            if type_id == TypeId::of::<NTransform>() {
+                if let Some(tick) = tick {
+                    interpolation::buffer_snapshot(world, entity, tick);
+                }
             }
         }
+    });
+}
+
+fn remove_component(world: &mut World, entity: Entity, type_id: TypeId) {
+    world.resource_scope(|world, register: Mut<TypeRegistry>| {
+        let read_registry = register.read();
+        match read_registry.get(type_id) {
+            Some(registration) => registration
+                .data::<ReflectComponent>()
+                .unwrap()
+                .remove(world, entity),
+            None => warn!("skipping unregistered component {:?}", type_id),
+        }
+    });
+}
+
+/// Resolves entity references in a received component, which arrive as network ids
+///
+/// Entities we haven't heard of yet are spawned empty, the server fills them in later.
+fn map_to_local(world: &mut World, component: &mut dyn Reflect) {
+    world.resource_scope(|world, mut network_entities: Mut<NetworkEntityMap>| {
+        map_entities(component, &mut |wire| {
+            let network_entity = entity_mapping::network_entity(wire);
+            let entity = network_entities
+                .get_local(&network_entity)
+                .unwrap_or_else(|| {
+                    let entity = world.spawn().insert(network_entity).id();
+                    network_entities.insert(network_entity, entity);
+                    entity
+                });
+            Some(entity)
+        })
+        .expect("unknown entities are spawned, so every reference maps");
+    });
+}
+
+fn find_or_spawn(
+    network_entity: &NetworkEntity,
+    network_entities: &mut NetworkEntityMap,
+    commands: &mut Commands,
+) -> Entity {
+    if let Some(entity) = network_entities.get_local(network_entity) {
+        entity
+    } else {
+        debug!("spawned a new entity: {:?}", &network_entity);
+        let entity = commands.spawn_bundle((*network_entity,)).id();
+        network_entities.insert(*network_entity, entity);
+        entity
     }
 }
 
-// fn translate_sprites(
-//     mut commands: Commands,
-//     mut nsprites: Query<(Entity, &NSprite, Option<&mut TextureAtlasSprite>)>,
-//     game_assets: Res<GameAssets>,
-// ) {
-//     for (entity, n_sprite, mut sprite) in nsprites.iter_mut() {
-//         if let Some(mut sprite) = sprite {
-//             sprite.index = n_sprite.sprite_index as usize;
-//         } else {
-//             debug!("tetsingasf");
-//             commands.entity(entity).insert_bundle(SpriteSheetBundle {
-//                 sprite: TextureAtlasSprite::new(n_sprite.sprite_index as usize),
-//                 texture_atlas: game_assets.sprite_atlas.clone(),
-//                 ..Default::default()
-//             });
-//         }
-//     }
-// }
-
-fn translate_transfom(
+fn play_effects(mut effects: EventReader<PlayEffect>) {
+    for effect in effects.iter() {
+        debug!("playing effect {} on {:?}", effect.effect, effect.entity);
+    }
+}
+
+fn show_world_settings(settings: Option<Res<WorldSettings>>) {
+    if let Some(settings) = settings.filter(|settings| settings.is_changed()) {
+        debug!("world size is {:?}", settings.size);
+    }
+}
+
+/// Attaches [`PlayerControlled`] to the entity the server says we control
+fn take_control(
+    mut commands: Commands,
+    controlled: Query<Entity, Added<NControlled>>,
+    released: Query<Entity, (With<PlayerControlled>, Without<NControlled>)>,
+) {
+    for entity in controlled.iter() {
+        debug!("controlling {:?}", entity);
+        commands.entity(entity).insert(PlayerControlled);
+    }
+    for entity in released.iter() {
+        commands.entity(entity).remove::<PlayerControlled>();
+    }
+}
+
+/// Colliders that aren't obstacles are creatures
+type NewCreatures = (Added<NCollider>, Without<NObstacle>);
+
+/// Gives creatures a sprite sheet to play their replicated animation on
+///
+/// The server only sends animations of creatures nearby, the others keep the animation they
+/// last had, so creatures get their sprite whether they have one yet or not.
+fn add_animators(
+    mut commands: Commands,
+    game_assets: Res<GameAssets>,
+    creatures: Query<Entity, NewCreatures>,
+) {
+    for entity in creatures.iter() {
+        commands.entity(entity).insert(game_assets.creature.clone());
+    }
+}
+
+type AnimationStarted = Or<(Changed<NAnimation>, Added<Animator>)>;
+
+/// Starts replicated animations where the server has them at the tick we render
+fn play_animations(
+    time: Res<Time>,
+    clock: Res<ServerClock>,
+    interpolation: Res<InterpolationSettings>,
+    mut animated: Query<(&NAnimation, &mut Animator, &mut TextureAtlasSprite), AnimationStarted>,
+) {
+    let render_tick = interpolation.render_tick(&clock, time.seconds_since_startup());
+
+    for (animation, mut animator, mut sprite) in animated.iter_mut() {
+        animator.set_animation(&animation.name);
+        if let Some(render_tick) = render_tick {
+            let elapsed = (render_tick - animation.start_tick as f64) / TICK_RATE;
+            animator.seek(Duration::from_secs_f64(elapsed.max(0.)));
+        }
+        sprite.index = animator.current_frame();
+    }
+}
+
+/// Draws obstacles as flat circles
+fn show_obstacles(
     mut commands: Commands,
-    mut entities: Query<(
-        Entity,
-        &NTransform,
-        Option<&mut Transform>,
-        Option<&TextureAtlasSprite>,
-    )>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    obstacles: Query<(Entity, &NCollider, &NTransform), Added<NObstacle>>,
 ) {
-    for (entity, nt, maybe_transform, s) in entities.iter_mut() {
-        if let Some(mut transform) = maybe_transform {
-            let new_transform = nt.as_transform();
-            transform.translation = new_transform.translation;
-            debug!("new translation: {:?}", &transform.translation);
-            debug!("entity {:?} has sprite: {}", &entity, s.is_some())
-            //*transform = nt.as_transform();
-        } else {
-            commands.entity(entity).insert(nt.as_transform());
-            debug!("entity had not transform");
+    for (entity, collider, n_transform) in obstacles.iter() {
+        commands.entity(entity).insert_bundle(MaterialMesh2dBundle {
+            mesh: meshes
+                .add(shape::Circle::new(collider.radius).into())
+                .into(),
+            material: materials.add(ColorMaterial::from(Color::DARK_GRAY)),
+            transform: n_transform.as_transform(),
+            ..Default::default()
+        });
+    }
+}
+
+/// Mirrors replicated parent references into the local hierarchy
+fn apply_parents(
+    mut commands: Commands,
+    changed: Query<(Entity, &NParent, Option<&Parent>), Changed<NParent>>,
+) {
+    for (entity, n_parent, parent) in changed.iter() {
+        let current = parent.map(|p| p.get());
+        if n_parent.parent == current {
+            continue;
+        }
+
+        match n_parent.parent {
+            Some(new_parent) => {
+                commands.entity(new_parent).add_child(entity);
+            }
+            None => {
+                if let Some(old_parent) = current {
+                    commands.entity(old_parent).remove_children(&[entity]);
+                }
+            }
         }
     }
 }
 
+/// Drops mappings for networked entities that were despawned locally
+fn forget_despawned_entities(
+    removed: RemovedComponents<NetworkEntity>,
+    mut network_entities: ResMut<NetworkEntityMap>,
+) {
+    for entity in removed.iter() {
+        network_entities.remove_local(&entity);
+    }
+}
+
 fn spawn_websocket_client(mut commands: Commands) {
     let (player_message_sender, mut player_message_receiver) =
         futures::channel::mpsc::channel::<messages::PlayerMessage>(512);
@@ -180,13 +441,28 @@ fn spawn_websocket_client(mut commands: Commands) {
     io_pool
         .spawn(async move {
             debug!("connecting to server");
-            let (_ws_meta, ws_stream) = WsMeta::connect("ws://127.0.0.1:13037/", None)
-                .await
-                .unwrap();
+            let (_ws_meta, ws_stream) =
+                match WsMeta::connect("ws://127.0.0.1:13037/", None).await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        error!("failed to connect to server: {}", e);
+                        return;
+                    }
+                };
             debug!("connected to server");
 
             let (mut ws_write, mut ws_read) = ws_stream.split();
 
+            // the server doesn't send us anything until it knows who we are
+            let hello = messages::PlayerMessage::Hello { my_id: player_id };
+            if let Err(e) = ws_write
+                .send(WsMessage::Binary(postcard::to_allocvec(&hello).unwrap()))
+                .await
+            {
+                error!("failed to say hello to the server: {}", e);
+                return;
+            }
+
             loop {
                 // see if we want to send anything
                 let mut pending_send = player_message_receiver.next().fuse();
@@ -224,18 +500,24 @@ fn spawn_websocket_client(mut commands: Commands) {
     commands.insert_resource(player_id);
 }
 
-struct GameAssets {}
+struct GameAssets {
+    creature: Handle<AnimatorArchetype>,
+}
 
-fn setup(mut commands: Commands) {
-    commands.insert_resource(GameAssets {});
+fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(GameAssets {
+        creature: asset_server.load("creature-sheet.aseprite.json"),
+    });
 
     commands.spawn_bundle(Camera2dBundle::default());
 }
 
 fn player_input(
+    mut commands: Commands,
     windows: Res<Windows>,
     buttons: Res<Input<MouseButton>>,
     q_camera: Query<(&Camera, &GlobalTransform)>,
+    controlled: Query<Entity, With<PlayerControlled>>,
 ) {
     // get the camera info and transform
     // assuming there is exactly one main camera entity, so query::single() is OK
@@ -252,7 +534,7 @@ fn player_input(
     if let Some(screen_pos) = wnd.cursor_position() {
         if buttons.just_pressed(MouseButton::Left) {
             // get the size of the window
-            let window_size = Vec2::new(wnd.width() as f32, wnd.height() as f32);
+            let window_size = Vec2::new(wnd.width(), wnd.height());
 
             // convert screen position [0..resolution] to ndc [-1..1] (gpu coordinates)
             let ndc = (screen_pos / window_size) * 2.0 - Vec2::ONE;
@@ -266,6 +548,14 @@ fn player_input(
 
             // reduce it to a 2D value
             world_pos.z = 0.;
+            debug!("clicked at {:?}", world_pos);
+
+            // prediction picks the new target up and sends it to the server
+            for entity in controlled.iter() {
+                commands
+                    .entity(entity)
+                    .insert(MoveTarget(world_pos.truncate()));
+            }
         }
     }
 }
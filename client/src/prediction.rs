@@ -0,0 +1,333 @@
+//! Client-side prediction of the entity the player controls
+//!
+//! Inputs move the [`PlayerControlled`] entity locally right away instead of waiting a round
+//! trip for the server. Every input gets a sequence number and stays in the [`InputHistory`]
+//! until a state from the server reflects it. When an authoritative
+//! [`NTransform`](shared_components::NTransform) arrives, the prediction restarts from it, at
+//! the tick of the batch it came in, and replays the inputs that state doesn't include yet.
+//! The jump that causes is not shown at once, it is blended out over a few frames.
+
+use crate::clock::ServerClock;
+use crate::interpolation::{InterpolationSettings, TransformSnapshots};
+use crate::PlayerControlled;
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+use futures::channel::mpsc::Sender;
+use messages::{PlayerMessage, Tick};
+use shared_components::collision::{clamp_move_targets, StaticCollision};
+use shared_components::movement::{self, MoveTarget, MovementSettings};
+use shared_components::navigation::{update_nav_grid, NavGrid, Path};
+use shared_components::NCollider;
+use std::collections::VecDeque;
+
+/// Unacknowledged inputs beyond this are forgotten, oldest first
+const MAX_PENDING_INPUTS: usize = 128;
+/// The prediction catches up at most this many ticks per frame, and jumps if further behind
+const MAX_CATCH_UP: Tick = 60;
+/// Fraction of the remaining correction that is blended out per second
+const CORRECTION_RATE: f32 = 10.;
+
+struct PendingInput {
+    sequence: u32,
+    /// Tick the input is first applied at, as predicted when it was made
+    tick: Tick,
+    /// Where the entity was predicted to be when the input was made
+    from: Vec2,
+    /// Path planned from there, the server plans the same one and walks it to the end
+    path: Path,
+    /// Tick of the batch that acknowledged the input, the server applies it from the next one
+    acked_at: Option<Tick>,
+}
+
+impl PendingInput {
+    /// Tick the input is first applied at, known for sure once it is acknowledged
+    fn applied_at(&self) -> Tick {
+        self.acked_at.map_or(self.tick, |acked_at| acked_at + 1)
+    }
+
+    /// How far along its path the server is at `tick`, found by walking it from the start
+    fn walk_at(
+        &self,
+        tick: Tick,
+        settings: &MovementSettings,
+        collision: &StaticCollision,
+        radius: f32,
+    ) -> Walk {
+        let mut walk = Walk(Some(self.path.clone()));
+        let mut translation = self.from;
+        for _ in self.applied_at()..=tick {
+            if walk.0.is_none() {
+                break;
+            }
+            translation = walk.step(translation, settings, collision, radius);
+        }
+        walk
+    }
+}
+
+/// Inputs the server hasn't acknowledged yet, or whose effect no received state shows yet
+#[derive(Default)]
+pub struct InputHistory {
+    next_sequence: u32,
+    pending: VecDeque<PendingInput>,
+    /// The latest input a received state reflects, the server walks its path
+    current: Option<PendingInput>,
+}
+
+impl InputHistory {
+    /// Remembers an input until it is acknowledged, returns its sequence number
+    pub fn record(&mut self, tick: Tick, from: Vec2, path: Path) -> u32 {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        self.pending.push_back(PendingInput {
+            sequence,
+            tick,
+            from,
+            path,
+            acked_at: None,
+        });
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        sequence
+    }
+
+    /// Marks every input up to `sequence` as applied by the server, in the batch of `tick`
+    pub fn ack(&mut self, sequence: u32, tick: Tick) {
+        for input in self.pending.iter_mut() {
+            if input.sequence > sequence {
+                break;
+            }
+            input.acked_at.get_or_insert(tick);
+        }
+    }
+
+    /// Forgets every input, they belonged to an entity we no longer control
+    ///
+    /// Sequence numbers keep counting, so acks for the old inputs match nothing.
+    fn reset(&mut self) {
+        self.pending.clear();
+        self.current = None;
+    }
+
+    /// Forgets the inputs that a state the server sent at `tick` already reflects
+    fn confirm(&mut self, tick: Tick) {
+        while let Some(input) = self.pending.front() {
+            if input.acked_at.map_or(true, |acked_at| acked_at > tick) {
+                break;
+            }
+            self.current = self.pending.pop_front();
+        }
+    }
+}
+
+/// The path the prediction walks along, if it is walking
+#[derive(Default)]
+struct Walk(Option<Path>);
+
+impl Walk {
+    /// One tick of movement, the same as on the server, forgets the path on arrival
+    ///
+    /// Other movers pushing the entity around aren't predicted, only obstacles and the world
+    /// bounds.
+    fn step(
+        &mut self,
+        translation: Vec2,
+        settings: &MovementSettings,
+        collision: &StaticCollision,
+        radius: f32,
+    ) -> Vec2 {
+        let path = match &mut self.0 {
+            Some(path) => path,
+            None => return translation,
+        };
+
+        let translation = movement::follow_path(translation, path, settings);
+        if path.is_finished() {
+            self.0 = None;
+        }
+        collision.resolve(translation, radius)
+    }
+}
+
+/// Locally simulated state of the controlled entity
+#[derive(Component)]
+pub struct Predicted {
+    translation: Vec2,
+    walk: Walk,
+    /// Tick the simulation is at
+    tick: Tick,
+    /// Offset between what is shown and the prediction, blended out over time
+    correction: Vec2,
+}
+
+fn radius(collider: Option<&NCollider>) -> f32 {
+    collider.map_or(0., |collider| collider.radius)
+}
+
+type NotYetPredicted = (With<PlayerControlled>, Without<Predicted>);
+
+fn start_predicting(
+    mut commands: Commands,
+    mut history: ResMut<InputHistory>,
+    controlled: Query<(Entity, &TransformSnapshots), NotYetPredicted>,
+) {
+    for (entity, snapshots) in controlled.iter() {
+        let (tick, n_transform) = match snapshots.latest() {
+            Some(latest) => latest,
+            None => continue,
+        };
+        // inputs of a previous avatar, say before a room switch, don't apply to this one
+        history.reset();
+        commands.entity(entity).insert(Predicted {
+            translation: n_transform.translation,
+            walk: Walk::default(),
+            tick,
+            correction: Vec2::ZERO,
+        });
+    }
+}
+
+/// A new target, or one set before the prediction started
+type NewInputs = Or<(Changed<MoveTarget>, Added<Predicted>)>;
+
+/// Applies new targets locally and sends them to the server
+fn record_inputs(
+    time: Res<Time>,
+    clock: Res<ServerClock>,
+    interpolation: Res<InterpolationSettings>,
+    mut history: ResMut<InputHistory>,
+    mut nav: ResMut<NavGrid>,
+    mut sender: ResMut<Sender<PlayerMessage>>,
+    mut controlled: Query<(&MoveTarget, &mut Predicted), NewInputs>,
+) {
+    // the server checks what was clicked on as we saw it
+    let view_tick = interpolation
+        .render_tick(&clock, time.seconds_since_startup())
+        .unwrap_or_default();
+
+    for (target, mut predicted) in controlled.iter_mut() {
+        let target = target.0;
+        // planned once, from where the server will be when it applies the input
+        let path = nav.find_path(predicted.translation, target);
+        let sequence = history.record(predicted.tick + 1, predicted.translation, path.clone());
+        predicted.walk = Walk(Some(path));
+
+        if sender
+            .try_send(PlayerMessage::MoveTo {
+                target,
+                sequence,
+                view_tick,
+            })
+            .is_err()
+        {
+            warn!("failed to queue move input {} for the server", sequence);
+        }
+    }
+}
+
+/// Restarts the prediction from the latest authoritative state
+fn reconcile(
+    mut history: ResMut<InputHistory>,
+    settings: Res<MovementSettings>,
+    collision: StaticCollision,
+    mut controlled: Query<
+        (&TransformSnapshots, Option<&NCollider>, &mut Predicted),
+        Changed<TransformSnapshots>,
+    >,
+) {
+    for (snapshots, collider, mut predicted) in controlled.iter_mut() {
+        let (server_tick, n_transform) = match snapshots.latest() {
+            Some(latest) => latest,
+            None => continue,
+        };
+        // acknowledged inputs that apply after this state are replayed like unacknowledged ones
+        history.confirm(server_tick);
+
+        let radius = radius(collider);
+        let mut translation = n_transform.translation;
+        // the server keeps walking the path of the latest input it applied
+        let mut walk = match &history.current {
+            Some(input) => input.walk_at(server_tick, &settings, &collision, radius),
+            None => Walk::default(),
+        };
+        let mut inputs = history.pending.iter().peekable();
+
+        let end = predicted.tick.max(server_tick);
+        for tick in server_tick + 1..=end {
+            while let Some(input) = inputs.next_if(|input| input.applied_at() <= tick) {
+                walk = Walk(Some(input.path.clone()));
+            }
+            translation = walk.step(translation, &settings, &collision, radius);
+        }
+        if let Some(input) = inputs.last() {
+            walk = Walk(Some(input.path.clone()));
+        }
+
+        let shown = predicted.translation + predicted.correction;
+        predicted.correction = shown - translation;
+        predicted.translation = translation;
+        predicted.walk = walk;
+        predicted.tick = end;
+    }
+}
+
+fn predict(
+    time: Res<Time>,
+    clock: Res<ServerClock>,
+    settings: Res<MovementSettings>,
+    collision: StaticCollision,
+    mut controlled: Query<(&mut Predicted, Option<&NCollider>, &mut Transform)>,
+) {
+    let now = match clock.tick_at(time.seconds_since_startup()) {
+        Some(tick) => tick.max(0.) as Tick,
+        None => return,
+    };
+
+    for (mut predicted, collider, mut transform) in controlled.iter_mut() {
+        let radius = radius(collider);
+        if now > predicted.tick + MAX_CATCH_UP {
+            predicted.tick = now - MAX_CATCH_UP;
+        }
+        let Predicted {
+            translation,
+            walk,
+            tick,
+            ..
+        } = &mut *predicted;
+        while *tick < now {
+            *translation = walk.step(*translation, &settings, &collision, radius);
+            *tick += 1;
+        }
+
+        predicted.correction *= (1. - CORRECTION_RATE * time.delta_seconds()).max(0.);
+        let shown = predicted.translation + predicted.correction;
+        transform.translation = shown.extend(transform.translation.z);
+    }
+}
+
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InputHistory>()
+            .init_resource::<NavGrid>()
+            .add_system_to_stage(CoreStage::PostUpdate, update_nav_grid.before(record_inputs))
+            // inputs made before this are picked up once it is done, see `NewInputs`
+            .add_system_to_stage(CoreStage::PostUpdate, start_predicting)
+            // the server moves targets the same way before applying them
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                clamp_move_targets.before(record_inputs),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, record_inputs)
+            .add_system_to_stage(CoreStage::PostUpdate, reconcile.after(record_inputs))
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                predict
+                    .after(reconcile)
+                    .before(TransformSystem::TransformPropagate),
+            );
+    }
+}
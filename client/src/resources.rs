@@ -0,0 +1,78 @@
+//! Global game state replicated from the server
+//!
+//! [`ServerMessage::ResourceChanged`]s are decoded and inserted as bevy resources, replacing the
+//! previous value. Each resource type has to be registered with [`NetworkResourceAppExt`].
+//!
+//! [`ServerMessage::ResourceChanged`]: messages::ServerMessage::ResourceChanged
+
+use crate::HandleServerMessages;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use messages::NetworkEntityMap;
+use shared_components::entity_mapping::{self, map_entities};
+use shared_components::NetworkResource;
+
+/// Latest raw value of each resource received this frame, by kind id
+#[derive(Default)]
+pub struct ReceivedResources(HashMap<u16, Vec<u8>>);
+
+impl ReceivedResources {
+    pub fn push(&mut self, kind: u16, data: Vec<u8>) {
+        self.0.insert(kind, data);
+    }
+}
+
+fn receive_server_resource<R: NetworkResource>(
+    mut commands: Commands,
+    mut received: ResMut<ReceivedResources>,
+    network_entities: Res<NetworkEntityMap>,
+) {
+    let data = match received.0.remove(&R::KIND_ID) {
+        Some(data) => data,
+        None => return,
+    };
+
+    let mut resource: R = match postcard::from_bytes(&data) {
+        Ok(resource) => resource,
+        Err(e) => {
+            warn!("failed to decode resource {}: {}", R::KIND_ID, e);
+            return;
+        }
+    };
+
+    if let Err(e) = map_entities(&mut resource, &mut |wire| {
+        network_entities.get_local(&entity_mapping::network_entity(wire))
+    }) {
+        warn!("dropping resource {}: {}", R::KIND_ID, e);
+        return;
+    }
+
+    commands.insert_resource(resource);
+}
+
+/// Drops resources nobody registered
+fn discard_unknown_resources(mut received: ResMut<ReceivedResources>) {
+    for (kind, _) in received.0.drain() {
+        debug!("dropping resource of unknown kind {}", kind);
+    }
+}
+
+pub trait NetworkResourceAppExt {
+    /// Registers a resource that is replicated from the server
+    fn add_networked_resource<R: NetworkResource>(&mut self) -> &mut Self;
+}
+
+impl NetworkResourceAppExt for App {
+    fn add_networked_resource<R: NetworkResource>(&mut self) -> &mut Self {
+        self.add_system(receive_server_resource::<R>.after(HandleServerMessages))
+    }
+}
+
+pub struct NetworkResourcePlugin;
+
+impl Plugin for NetworkResourcePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReceivedResources>()
+            .add_system_to_stage(CoreStage::Last, discard_unknown_resources);
+    }
+}
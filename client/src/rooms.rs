@@ -0,0 +1,34 @@
+//! The room the player is in
+//!
+//! Room requests are chat commands the server runs, and its answers arrive as room messages,
+//! which become [`RoomNotice`]s for the chat box to show. The window title says which room
+//! the player is in.
+
+use bevy::prelude::*;
+use messages::{RoomId, RoomInfo};
+
+/// An answer to a room request
+#[derive(Debug, Clone)]
+pub enum RoomNotice {
+    List(Vec<RoomInfo>),
+    Joined { room: RoomId, name: String },
+    Refused(String),
+}
+
+fn show_room_in_title(mut notices: EventReader<RoomNotice>, mut windows: ResMut<Windows>) {
+    for notice in notices.iter() {
+        if let RoomNotice::Joined { room, name } = notice {
+            if let Some(window) = windows.get_primary_mut() {
+                window.set_title(format!("{} (room {})", name, room));
+            }
+        }
+    }
+}
+
+pub struct RoomPlugin;
+
+impl Plugin for RoomPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RoomNotice>().add_system(show_room_in_title);
+    }
+}
@@ -0,0 +1,62 @@
+//! Drawing the map of the world
+//!
+//! The map arrives as a replicated [`Tilemap`] resource. Every tile becomes a sprite cut from
+//! the tileset image, layered below everything else, and all of them are replaced when the map
+//! changes.
+
+use bevy::prelude::*;
+use shared_components::Tilemap;
+
+/// Depth of the lowest tile layer, each layer above is drawn a little closer
+const TILE_DEPTH: f32 = -10.;
+const LAYER_DEPTH: f32 = 0.1;
+
+#[derive(Component)]
+struct TileSprite;
+
+fn spawn_tiles(
+    mut commands: Commands,
+    map: Option<Res<Tilemap>>,
+    asset_server: Res<AssetServer>,
+    mut atlases: ResMut<Assets<TextureAtlas>>,
+    old_tiles: Query<Entity, With<TileSprite>>,
+) {
+    let map = match map {
+        Some(map) if map.is_changed() => map,
+        _ => return,
+    };
+
+    for entity in old_tiles.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let columns = map.tileset_columns.max(1);
+    let rows = map.tiles.iter().map(|tile| tile.sprite / columns + 1).max();
+    let atlas = atlases.add(TextureAtlas::from_grid(
+        asset_server.load(&map.tileset),
+        Vec2::splat(map.tile_size),
+        columns as usize,
+        rows.unwrap_or(1) as usize,
+    ));
+
+    for (x, y, tile) in map.iter() {
+        let depth = TILE_DEPTH + tile.layer as f32 * LAYER_DEPTH;
+        commands
+            .spawn_bundle(SpriteSheetBundle {
+                sprite: TextureAtlasSprite::new(tile.sprite as usize),
+                texture_atlas: atlas.clone(),
+                transform: Transform::from_translation(map.tile_center(x, y).extend(depth)),
+                ..Default::default()
+            })
+            .insert(TileSprite);
+    }
+    debug!("showing a {}x{} map", map.width, map.height);
+}
+
+pub struct TilemapPlugin;
+
+impl Plugin for TilemapPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(spawn_tiles);
+    }
+}
@@ -2,15 +2,47 @@
 
 use bevy::ecs::component::Component;
 use bevy::ecs::entity::Entity;
+use bevy::math::Vec2;
+use bevy::utils::HashMap;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 
+/// Server ticks per second, the server advances [`Tick`] once per frame at this rate
+pub const TICK_RATE: f64 = 30.;
+
+/// A server frame number
+pub type Tick = u32;
+
 pub trait KindId {
     const KIND_ID: u16;
+    const DELIVERY: Delivery = Delivery::Reliable;
+    const VISIBILITY: VisibilityRule = VisibilityRule::Everyone;
 }
 
+/// How updates of a networked kind should be delivered
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Delivery {
+    /// Every update arrives, in order. Use for spawns, inventories and the like.
+    Reliable,
+    /// Only the latest update per entity matters, older ones may be dropped while queued.
+    /// Use for state that is resent whenever it changes, like transforms.
+    Unreliable,
+}
+
+/// Which clients get updates of a networked kind
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum VisibilityRule {
+    Everyone,
+    /// Only the player whose [`PlayerId`] is on the entity
+    OwnerOnly,
+    /// Only the connections listed in the entity's visibility set, which gameplay systems keep
+    /// up to date
+    Filtered,
+}
+
+/// Identifies a player across connections, also marks the entities a player owns
 #[repr(transparent)]
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, Hash)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Component)]
 pub struct PlayerId(bevy::utils::Uuid);
 
 impl PlayerId {
@@ -19,28 +51,173 @@ impl PlayerId {
     }
 }
 
+impl Default for PlayerId {
+    fn default() -> Self {
+        PlayerId::new()
+    }
+}
+
 impl Display for PlayerId {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
+/// Identifies a room, a separate set of entities players can be in
+///
+/// Every player is in exactly one room, starting in the [`RoomId::LOBBY`], and only gets the
+/// entities of their room. The server keeps it as a component on the entities of a room;
+/// entities without one, like the map and obstacles, are shared by every room.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Component)]
+pub struct RoomId(pub u32);
+
+impl RoomId {
+    /// The room players join in, which is never cleaned up
+    pub const LOBBY: RoomId = RoomId(0);
+}
+
+impl Display for RoomId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A room as listed in [`ServerMessage::RoomList`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub id: RoomId,
+    pub name: String,
+    pub players: u32,
+}
+
+/// Network id of a replicated entity
+///
+/// Ids are handed out by the server when an entity starts replicating and are never reused, so
+/// they don't leak server `Entity` internals and stay stable across server-side entity churn.
+/// Both the server and the client keep this as a component on the replicated entity.
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq, Component, Hash)]
-pub struct NetworkEntity(u64);
+pub struct NetworkEntity(u32);
+
+impl NetworkEntity {
+    pub fn from_raw(id: u32) -> Self {
+        NetworkEntity(id)
+    }
+
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Display for NetworkEntity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Bidirectional mapping between network ids and local entities
+#[derive(Debug, Default)]
+pub struct NetworkEntityMap {
+    to_local: HashMap<NetworkEntity, Entity>,
+    to_network: HashMap<Entity, NetworkEntity>,
+}
+
+impl NetworkEntityMap {
+    pub fn insert(&mut self, network_entity: NetworkEntity, entity: Entity) {
+        if let Some(old_entity) = self.to_local.insert(network_entity, entity) {
+            self.to_network.remove(&old_entity);
+        }
+        if let Some(old_network_entity) = self.to_network.insert(entity, network_entity) {
+            if old_network_entity != network_entity {
+                self.to_local.remove(&old_network_entity);
+            }
+        }
+    }
+
+    pub fn get_local(&self, network_entity: &NetworkEntity) -> Option<Entity> {
+        self.to_local.get(network_entity).copied()
+    }
+
+    pub fn get_network(&self, entity: &Entity) -> Option<NetworkEntity> {
+        self.to_network.get(entity).copied()
+    }
+
+    pub fn remove_local(&mut self, entity: &Entity) -> Option<NetworkEntity> {
+        let network_entity = self.to_network.remove(entity)?;
+        self.to_local.remove(&network_entity);
+        Some(network_entity)
+    }
+
+    pub fn remove_network(&mut self, network_entity: &NetworkEntity) -> Option<Entity> {
+        let entity = self.to_local.remove(network_entity)?;
+        self.to_network.remove(&entity);
+        Some(entity)
+    }
+
+    pub fn len(&self) -> usize {
+        self.to_local.len()
+    }
 
-impl From<&Entity> for NetworkEntity {
-    fn from(e: &Entity) -> Self {
-        NetworkEntity((e.generation() as u64) << 32 | (e.id() as u64))
+    pub fn is_empty(&self) -> bool {
+        self.to_local.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NetworkEntity, Entity)> + '_ {
+        self.to_local.iter().map(|(n, e)| (*n, *e))
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PlayerMessage {
-    Hello { my_id: PlayerId },
+    Hello {
+        my_id: PlayerId,
+    },
+    Event {
+        kind: u16,
+        data: Vec<u8>,
+    },
+    /// Click-to-move input, acknowledged with a [`ServerMessage::InputAck`] carrying the same
+    /// sequence number. `view_tick` is the server tick the client was showing other entities
+    /// at when the player clicked.
+    MoveTo {
+        target: Vec2,
+        sequence: u32,
+        view_tick: f64,
+    },
+    /// Asks for a [`ServerMessage::Pong`], `sent_at` is the client's clock in seconds
+    Ping {
+        sent_at: f64,
+    },
+    /// Creates a room and moves this player into it, answered with a
+    /// [`ServerMessage::RoomJoined`]
+    CreateRoom {
+        name: String,
+    },
+    /// Asks for a [`ServerMessage::RoomList`]
+    ListRooms,
+    /// Moves this player into another room, answered with a [`ServerMessage::RoomJoined`]
+    JoinRoom {
+        room: RoomId,
+    },
+    /// Moves this player back to the lobby
+    LeaveRoom,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
+    /// Starts the batch of messages sent at this server tick
+    Tick {
+        tick: Tick,
+    },
+    /// The latest input of this player the server applied, updates of the player's entity in
+    /// this batch already reflect it
+    InputAck {
+        sequence: u32,
+    },
+    /// Answers a [`PlayerMessage::Ping`] with the tick it was received at
+    Pong {
+        sent_at: f64,
+        tick: Tick,
+    },
     Welcome {
         players: Vec<PlayerId>,
     },
@@ -49,6 +226,11 @@ pub enum ServerMessage {
         world: Vec<u8>,
         players: Vec<PlayerId>,
     },
+    /// A new entity, with all of its networked components as `(kind, data)` pairs
+    EntitySpawned {
+        entity: NetworkEntity,
+        components: Vec<(u16, Vec<u8>)>,
+    },
     ComponentAdded {
         entity: NetworkEntity,
         component: u16,
@@ -59,4 +241,35 @@ pub enum ServerMessage {
         component: u16,
         data: Vec<u8>,
     },
+    /// The component is no longer visible to this client
+    ComponentRemoved {
+        entity: NetworkEntity,
+        component: u16,
+    },
+    EntityDespawned {
+        entity: NetworkEntity,
+    },
+    /// The new value of a replicated resource
+    ResourceChanged {
+        kind: u16,
+        data: Vec<u8>,
+    },
+    Event {
+        kind: u16,
+        data: Vec<u8>,
+    },
+    /// Answers a [`PlayerMessage::ListRooms`]
+    RoomList {
+        rooms: Vec<RoomInfo>,
+    },
+    /// This player is now in `room`, the entities of the room they were in are despawned
+    /// before this and the ones of the new room spawned after it, in the same batch
+    RoomJoined {
+        room: RoomId,
+        name: String,
+    },
+    /// A room request that couldn't be done
+    RoomRefused {
+        reason: String,
+    },
 }
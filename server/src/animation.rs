@@ -0,0 +1,103 @@
+//! Choosing which animation an entity plays
+//!
+//! The server only decides the animation by name, from gameplay state: entities walk while
+//! they have somewhere to go and idle otherwise. [`NAnimation`] changes, and is replicated,
+//! only when the animation does; clients play the frames themselves from its start tick.
+//!
+//! The server loads the same sprite sheet exports as the client, without their images, so it
+//! knows how long each animation takes. An animation plays at least through its lead-in, the
+//! frames before its loop, before another one takes over, so short walks still show their
+//! first step.
+
+use crate::tick::ServerTick;
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use messages::TICK_RATE;
+use shared_components::animation::{AnimationAssetPlugin, AnimationSet};
+use shared_components::movement::MoveTarget;
+use shared_components::NAnimation;
+
+pub const IDLE: &str = "idle";
+pub const WALK: &str = "walk";
+
+/// Runs once the animations of this tick are decided, before they are replicated
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct ChooseAnimations;
+
+/// Animation sets of the sprites entities are shown with
+pub struct AnimationSets {
+    pub creature: Handle<AnimationSet>,
+}
+
+fn load_animation_sets(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(AnimationSets {
+        creature: asset_server.load("creature-sheet.aseprite.json"),
+    });
+}
+
+fn log_loaded_animation_sets(
+    mut events: EventReader<AssetEvent<AnimationSet>>,
+    animation_sets: Res<AnimationSets>,
+    sets: Res<Assets<AnimationSet>>,
+) {
+    for event in events.iter() {
+        let creature = &animation_sets.creature;
+        if !matches!(event, AssetEvent::Created { handle } if handle == creature) {
+            continue;
+        }
+        let set = match sets.get(creature) {
+            Some(set) => set,
+            None => continue,
+        };
+        for name in set.names() {
+            debug!(
+                "creature animation '{}' takes {:?}, loops every {:?}",
+                name,
+                set.duration(name).unwrap_or_default(),
+                set.loop_duration(name).unwrap_or_default()
+            );
+        }
+    }
+}
+
+fn choose_animations(
+    tick: Res<ServerTick>,
+    animation_sets: Res<AnimationSets>,
+    sets: Res<Assets<AnimationSet>>,
+    mut animated: Query<(&mut NAnimation, Option<&MoveTarget>)>,
+) {
+    // until the set is loaded, animations switch right away
+    let set = sets.get(&animation_sets.creature);
+
+    for (mut animation, move_target) in animated.iter_mut() {
+        let name = if move_target.is_some() { WALK } else { IDLE };
+        if animation.name != name {
+            let elapsed = tick.0.saturating_sub(animation.start_tick) as f64 / TICK_RATE;
+            let lead_in = set
+                .and_then(|set| set.lead_in(&animation.name))
+                .unwrap_or_default();
+            if Duration::from_secs_f64(elapsed) < lead_in {
+                continue;
+            }
+            *animation = NAnimation {
+                name: name.into(),
+                start_tick: tick.0,
+            };
+        }
+    }
+}
+
+pub struct AnimationPlugin;
+
+impl Plugin for AnimationPlugin {
+    fn build(&self, app: &mut App) {
+        // targets added or removed during the update are applied by now
+        app.add_plugin(AnimationAssetPlugin)
+            .add_startup_system(load_animation_sets)
+            .add_system(log_loaded_animation_sets)
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                choose_animations.label(ChooseAnimations),
+            );
+    }
+}
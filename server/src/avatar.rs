@@ -0,0 +1,103 @@
+//! Player avatars
+//!
+//! Every player gets an avatar in the room they are in, owned through their [`PlayerId`] and
+//! marked with the owner-only [`NControlled`], so only their client knows it controls it.
+//! Moving to another room replaces the avatar with one in the new room, and the avatar is
+//! despawned when the player leaves.
+
+use crate::input::ApplyMoveInputs;
+use crate::network_id::Replicated;
+use crate::rooms::RoomChanged;
+use crate::visibility::{ConnectionMappings, NetworkVisibility, VisibleNearby};
+use crate::{random_position, PlayerEvent, CREATURE_RADIUS};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use messages::{PlayerId, RoomId};
+use shared_components::{NAnimation, NCollider, NControlled, NTransform, Tilemap, WorldSettings};
+
+/// Marks the entity a player controls
+#[derive(Component)]
+pub struct Avatar;
+
+fn spawn_avatars(
+    mut commands: Commands,
+    mut room_changes: EventReader<RoomChanged>,
+    connections: Res<ConnectionMappings>,
+    settings: Res<WorldSettings>,
+    // players joining before the map is loaded get pushed out of walls once it is
+    map: Option<Res<Tilemap>>,
+    avatars: Query<(Entity, &PlayerId), With<Avatar>>,
+) {
+    // only the room a player ends up in this frame gets an avatar
+    let moved: HashMap<u64, RoomId> = room_changes
+        .iter()
+        .map(|change| (change.connection_id, change.to))
+        .collect();
+
+    for (connection_id, to) in moved {
+        let player_id = match connections.player(connection_id) {
+            Some(player_id) => player_id,
+            None => continue,
+        };
+
+        for (avatar, _) in avatars.iter().filter(|(_, owner)| **owner == player_id) {
+            commands.entity(avatar).despawn_recursive();
+        }
+
+        let position = random_position(&settings, map.as_deref(), CREATURE_RADIUS);
+        let transform = Transform::from_translation(position.extend(0.));
+        let avatar = commands
+            .spawn_bundle(TransformBundle::from_transform(transform))
+            .insert(NTransform::from(transform))
+            .insert(NControlled::default())
+            .insert(NAnimation::default())
+            .insert(VisibleNearby)
+            .insert(NetworkVisibility::default())
+            .insert(NCollider {
+                radius: CREATURE_RADIUS,
+            })
+            .insert(player_id)
+            .insert(to)
+            .insert(Avatar)
+            .insert(Replicated)
+            .id();
+        debug!(
+            "spawned avatar {:?} for connection {} in room {}",
+            avatar, connection_id, to
+        );
+    }
+}
+
+fn despawn_avatars(
+    mut commands: Commands,
+    mut player_events: EventReader<PlayerEvent>,
+    avatars: Query<(Entity, &PlayerId), With<Avatar>>,
+) {
+    for event in player_events.iter() {
+        let (connection_id, player_id) = match event {
+            PlayerEvent::PlayerLeft {
+                connection_id,
+                player_id,
+            } => (connection_id, player_id),
+            _ => continue,
+        };
+
+        for (avatar, _) in avatars.iter().filter(|(_, owner)| *owner == player_id) {
+            debug!(
+                "despawning avatar {:?}, connection {} left",
+                avatar, connection_id
+            );
+            commands.entity(avatar).despawn_recursive();
+        }
+    }
+}
+
+pub struct AvatarPlugin;
+
+impl Plugin for AvatarPlugin {
+    fn build(&self, app: &mut App) {
+        // inputs may still target the old avatar, so it is despawned after they are applied
+        app.add_system(spawn_avatars.after(ApplyMoveInputs))
+            .add_system(despawn_avatars);
+    }
+}
@@ -0,0 +1,399 @@
+//! Chat between players
+//!
+//! Players chat on the global channel by default, which reaches every room, and reach players
+//! nearby or a single player with commands. Every line a player sends, command or not, takes a
+//! token from their bucket, which refills over time, so nobody can flood the others. Every
+//! relayed line goes to the [`ChatLog`], and joining players get the recent global lines from
+//! it.
+//!
+//! Commands:
+//! - `/help` lists the commands
+//! - `/name <name>` changes the name others see
+//! - `/who` lists who is online
+//! - `/w <name> <text>` or `/whisper <name> <text>` sends to one player
+//! - `/n <text>` or `/nearby <text>` sends to players near your avatar, in your room
+//! - `/rooms`, `/create <name>`, `/join <room>` and `/leave` do what the room messages do, see
+//!   [`crate::rooms`]
+
+use crate::avatar::Avatar;
+use crate::events::{FromClient, NetworkEventAppExt, Recipients, ToClients};
+use crate::rooms::{RoomAction, RoomRequest};
+use crate::visibility::ConnectionMappings;
+use crate::PlayerEvent;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use messages::{PlayerId, RoomId};
+use shared_components::chat::{ChatChannel, COMMAND_PREFIX, MAX_MESSAGE_LENGTH};
+use shared_components::spatial::SpatialGrid;
+use shared_components::{ChatMessage, SendChat};
+use std::collections::VecDeque;
+
+const HELP: &str = "commands: /help, /name <name>, /who, /w <name> <text>, /n <text>, /rooms, \
+                    /create <name>, /join <room>, /leave";
+/// Names are 1 to this many letters, digits, `-` or `_`
+const MAX_NAME_LENGTH: usize = 16;
+
+pub struct ChatSettings {
+    /// Lines a player can send in a row before being rate limited
+    pub burst: f32,
+    /// Lines a player gets back per second
+    pub lines_per_second: f32,
+    /// Nearby messages reach avatars this close to the sender's
+    pub nearby_radius: f32,
+    /// Lines kept in the chat log
+    pub log_length: usize,
+    /// Global lines joining players get
+    pub history_on_join: usize,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        ChatSettings {
+            burst: 5.,
+            lines_per_second: 1.,
+            nearby_radius: 300.,
+            log_length: 500,
+            history_on_join: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatLogEntry {
+    pub channel: ChatChannel,
+    pub from: String,
+    pub text: String,
+}
+
+/// The most recent relayed lines, the oldest first
+#[derive(Default)]
+pub struct ChatLog(pub VecDeque<ChatLogEntry>);
+
+struct Chatter {
+    name: String,
+    /// Lines left before being rate limited
+    tokens: f32,
+    /// When `tokens` was last refilled, in seconds since startup
+    refilled_at: f64,
+}
+
+impl Chatter {
+    /// Takes a token if there is one, after refilling for the time since the last line
+    fn try_spend(&mut self, now: f64, settings: &ChatSettings) -> bool {
+        let refill = (now - self.refilled_at) as f32 * settings.lines_per_second;
+        self.tokens = (self.tokens + refill).min(settings.burst);
+        self.refilled_at = now;
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
+}
+
+/// Chat state of every connected player, by connection
+#[derive(Default)]
+struct Chatters(HashMap<u64, Chatter>);
+
+impl Chatters {
+    fn find(&self, name: &str) -> Option<u64> {
+        self.0
+            .iter()
+            .find(|(_, chatter)| chatter.name.eq_ignore_ascii_case(name))
+            .map(|(connection_id, _)| *connection_id)
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LENGTH).contains(&name.chars().count())
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Splits a line into its first word and the rest
+fn split_word(line: &str) -> (&str, &str) {
+    match line.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (line, ""),
+    }
+}
+
+/// The room request a command stands for, or how to use it if its arguments are wrong
+fn room_action(command: &str, args: &str) -> Option<Result<RoomAction, &'static str>> {
+    let action = match command {
+        "rooms" => RoomAction::List,
+        "create" => RoomAction::Create { name: args.into() },
+        "join" => match args.parse() {
+            Ok(room) => RoomAction::Join { room: RoomId(room) },
+            Err(_) => return Some(Err("usage: /join <room number>")),
+        },
+        "leave" => RoomAction::Leave,
+        _ => return None,
+    };
+    Some(Ok(action))
+}
+
+fn message(channel: ChatChannel, from: &str, text: &str) -> ChatMessage {
+    ChatMessage {
+        channel: channel.as_u8(),
+        from: from.into(),
+        text: text.into(),
+    }
+}
+
+fn system_reply(connection_id: u64, text: impl Into<String>) -> ToClients<ChatMessage> {
+    ToClients {
+        recipients: Recipients::One(connection_id),
+        event: ChatMessage {
+            channel: ChatChannel::System.as_u8(),
+            from: String::new(),
+            text: text.into(),
+        },
+    }
+}
+
+fn track_chatters(
+    mut player_events: EventReader<PlayerEvent>,
+    mut chatters: ResMut<Chatters>,
+    log: Res<ChatLog>,
+    settings: Res<ChatSettings>,
+    time: Res<Time>,
+    mut messages: EventWriter<ToClients<ChatMessage>>,
+) {
+    for event in player_events.iter() {
+        match event {
+            PlayerEvent::PlayerJoined { connection_id } => {
+                chatters.0.insert(
+                    *connection_id,
+                    Chatter {
+                        name: format!("player-{}", connection_id),
+                        tokens: settings.burst,
+                        refilled_at: time.seconds_since_startup(),
+                    },
+                );
+
+                let history: Vec<_> = log
+                    .0
+                    .iter()
+                    .rev()
+                    .filter(|entry| entry.channel == ChatChannel::Global)
+                    .take(settings.history_on_join)
+                    .collect();
+                for entry in history.into_iter().rev() {
+                    messages.send(ToClients {
+                        recipients: Recipients::One(*connection_id),
+                        event: message(entry.channel, &entry.from, &entry.text),
+                    });
+                }
+            }
+            PlayerEvent::PlayerLeft { connection_id, .. } => {
+                chatters.0.remove(connection_id);
+            }
+        }
+    }
+}
+
+/// Connections of the players whose avatar is near the avatar of `player_id`, theirs included
+fn nearby_connections(
+    player_id: PlayerId,
+    radius: f32,
+    grid: &SpatialGrid,
+    avatars: &Query<(&PlayerId, &Transform, &RoomId), With<Avatar>>,
+    connections: &ConnectionMappings,
+) -> Vec<u64> {
+    // the sender's avatar may be too new to be in the grid yet
+    let (position, room) = match avatars.iter().find(|(owner, _, _)| **owner == player_id) {
+        Some((_, transform, room)) => (transform.translation.truncate(), room),
+        None => return Vec::new(),
+    };
+
+    let mut nearby: Vec<u64> = grid
+        .within_radius(position, radius)
+        .filter_map(|(entity, _)| avatars.get(entity).ok())
+        .filter(|(owner, _, other_room)| **owner != player_id && *other_room == room)
+        .filter_map(|(owner, _, _)| connections.connection(owner))
+        .collect();
+    nearby.extend(connections.connection(&player_id));
+    nearby
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_chat(
+    mut received: EventReader<FromClient<SendChat>>,
+    mut chatters: ResMut<Chatters>,
+    mut log: ResMut<ChatLog>,
+    settings: Res<ChatSettings>,
+    time: Res<Time>,
+    connections: Res<ConnectionMappings>,
+    grid: Res<SpatialGrid>,
+    avatars: Query<(&PlayerId, &Transform, &RoomId), With<Avatar>>,
+    mut room_requests: EventWriter<RoomRequest>,
+    mut messages: EventWriter<ToClients<ChatMessage>>,
+) {
+    for FromClient {
+        connection_id,
+        event,
+    } in received.iter()
+    {
+        let connection_id = *connection_id;
+        let chatter = match chatters.0.get_mut(&connection_id) {
+            Some(chatter) => chatter,
+            None => continue,
+        };
+
+        let text: String = event.text.chars().filter(|c| !c.is_control()).collect();
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        if text.chars().count() > MAX_MESSAGE_LENGTH {
+            messages.send(system_reply(
+                connection_id,
+                format!("messages are at most {} characters", MAX_MESSAGE_LENGTH),
+            ));
+            continue;
+        }
+        if !chatter.try_spend(time.seconds_since_startup(), &settings) {
+            messages.send(system_reply(connection_id, "slow down"));
+            continue;
+        }
+        let from = chatter.name.clone();
+
+        let (channel, recipients, text) = match text.strip_prefix(COMMAND_PREFIX) {
+            None => (ChatChannel::Global, Recipients::Everyone, text),
+            Some(command) => {
+                let (command, args) = split_word(command);
+                match room_action(command, args) {
+                    Some(Ok(action)) => {
+                        room_requests.send(RoomRequest {
+                            connection_id,
+                            action,
+                        });
+                        continue;
+                    }
+                    Some(Err(usage)) => {
+                        messages.send(system_reply(connection_id, usage));
+                        continue;
+                    }
+                    None => {}
+                }
+                match command {
+                    "help" => {
+                        messages.send(system_reply(connection_id, HELP));
+                        continue;
+                    }
+                    "who" => {
+                        let mut names: Vec<_> =
+                            chatters.0.values().map(|chatter| &chatter.name).collect();
+                        names.sort();
+                        let names: Vec<_> = names.into_iter().map(String::as_str).collect();
+                        messages.send(system_reply(
+                            connection_id,
+                            format!("online: {}", names.join(", ")),
+                        ));
+                        continue;
+                    }
+                    "name" => {
+                        let reply = if !is_valid_name(args) {
+                            format!("names are 1 to {} letters, digits, - or _", MAX_NAME_LENGTH)
+                        } else if chatters.find(args).is_some() {
+                            format!("{} is taken", args)
+                        } else {
+                            let notice = format!("{} is now known as {}", from, args);
+                            info!("chat: {}", notice);
+                            chatters.0.get_mut(&connection_id).unwrap().name = args.into();
+                            messages.send(ToClients {
+                                recipients: Recipients::Everyone,
+                                event: message(ChatChannel::System, "", &notice),
+                            });
+                            continue;
+                        };
+                        messages.send(system_reply(connection_id, reply));
+                        continue;
+                    }
+                    "w" | "whisper" => {
+                        let (name, text) = split_word(args);
+                        if text.is_empty() {
+                            messages.send(system_reply(connection_id, "usage: /w <name> <text>"));
+                            continue;
+                        }
+                        let to = match chatters.find(name) {
+                            Some(to) => to,
+                            None => {
+                                messages.send(system_reply(
+                                    connection_id,
+                                    format!("nobody is called {}", name),
+                                ));
+                                continue;
+                            }
+                        };
+                        // the sender sees their own whisper too
+                        (
+                            ChatChannel::Whisper,
+                            Recipients::Group(vec![connection_id, to]),
+                            text,
+                        )
+                    }
+                    "n" | "nearby" => {
+                        if args.is_empty() {
+                            messages.send(system_reply(connection_id, "usage: /n <text>"));
+                            continue;
+                        }
+                        let player_id = match connections.player(connection_id) {
+                            Some(player_id) => player_id,
+                            None => continue,
+                        };
+                        let nearby = nearby_connections(
+                            player_id,
+                            settings.nearby_radius,
+                            &grid,
+                            &avatars,
+                            &connections,
+                        );
+                        if nearby.is_empty() {
+                            messages.send(system_reply(connection_id, "you have no avatar"));
+                            continue;
+                        }
+                        (ChatChannel::Nearby, Recipients::Group(nearby), args)
+                    }
+                    _ => {
+                        messages.send(system_reply(
+                            connection_id,
+                            format!("unknown command /{}, try /help", command),
+                        ));
+                        continue;
+                    }
+                }
+            }
+        };
+
+        info!("chat [{:?}] {}: {}", channel, from, text);
+        log.0.push_back(ChatLogEntry {
+            channel,
+            from: from.clone(),
+            text: text.into(),
+        });
+        while log.0.len() > settings.log_length {
+            log.0.pop_front();
+        }
+        messages.send(ToClients {
+            recipients,
+            event: message(channel, &from, text),
+        });
+    }
+}
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatSettings>()
+            .init_resource::<ChatLog>()
+            .init_resource::<Chatters>()
+            .add_client_event::<SendChat>()
+            .add_server_event::<ChatMessage>()
+            .add_system(track_chatters)
+            .add_system(handle_chat.after(track_chatters));
+    }
+}
@@ -0,0 +1,160 @@
+//! Networked events to and from clients
+//!
+//! Events coming from clients are delivered as [`FromClient`] bevy events, tagged with the
+//! connection they came from. Events for clients are sent as [`ToClients`] and go to the
+//! listed [`Recipients`]. Each event type has to be registered with
+//! [`NetworkEventAppExt`].
+
+use crate::{Broadcast, BroadcastMessages, PumpMessages};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use messages::NetworkEntityMap;
+use shared_components::entity_mapping::{self, map_entities, wire_entity};
+use shared_components::NetworkEvent;
+
+/// Raw events received from clients this frame, by kind id
+#[derive(Default)]
+pub struct ReceivedEvents(HashMap<u16, Vec<(u64, Vec<u8>)>>);
+
+impl ReceivedEvents {
+    pub fn push(&mut self, connection_id: u64, kind: u16, data: Vec<u8>) {
+        self.0.entry(kind).or_default().push((connection_id, data));
+    }
+}
+
+/// An event sent by a client
+#[derive(Debug, Clone)]
+pub struct FromClient<E> {
+    pub connection_id: u64,
+    pub event: E,
+}
+
+/// Connections a server event is sent to
+#[derive(Debug, Clone)]
+pub enum Recipients {
+    One(u64),
+    Group(Vec<u64>),
+    Everyone,
+}
+
+impl Recipients {
+    pub fn contains(&self, connection_id: u64) -> bool {
+        match self {
+            Recipients::One(id) => *id == connection_id,
+            Recipients::Group(ids) => ids.contains(&connection_id),
+            Recipients::Everyone => true,
+        }
+    }
+}
+
+/// An event to send to clients
+#[derive(Debug, Clone)]
+pub struct ToClients<E> {
+    pub recipients: Recipients,
+    pub event: E,
+}
+
+fn receive_client_events<E: NetworkEvent>(
+    mut received: ResMut<ReceivedEvents>,
+    network_entities: Res<NetworkEntityMap>,
+    mut events: EventWriter<FromClient<E>>,
+) {
+    let pending = match received.0.remove(&E::KIND_ID) {
+        Some(pending) => pending,
+        None => return,
+    };
+
+    for (connection_id, data) in pending {
+        let mut event: E = match postcard::from_bytes(&data) {
+            Ok(event) => event,
+            Err(e) => {
+                debug!(
+                    "connection {}: failed to decode event {}: {}",
+                    connection_id,
+                    E::KIND_ID,
+                    e
+                );
+                continue;
+            }
+        };
+
+        // clients only know entities by network id, anything else is made up
+        if let Err(e) = map_entities(&mut event, &mut |wire| {
+            network_entities.get_local(&entity_mapping::network_entity(wire))
+        }) {
+            debug!(
+                "connection {}: dropping event {}: {}",
+                connection_id,
+                E::KIND_ID,
+                e
+            );
+            continue;
+        }
+
+        events.send(FromClient {
+            connection_id,
+            event,
+        });
+    }
+}
+
+fn send_server_events<E: NetworkEvent>(
+    mut events: EventReader<ToClients<E>>,
+    network_entities: Res<NetworkEntityMap>,
+    mut broadcasts: EventWriter<Broadcast>,
+) {
+    for ToClients { recipients, event } in events.iter() {
+        let mut event = event.clone();
+        if let Err(e) = map_entities(&mut event, &mut |e| {
+            network_entities.get_network(&e).map(wire_entity)
+        }) {
+            warn!("dropping event {}: {}", E::KIND_ID, e);
+            continue;
+        }
+
+        broadcasts.send(Broadcast::Event {
+            recipients: recipients.clone(),
+            kind: E::KIND_ID,
+            data: postcard::to_allocvec(&event).unwrap(),
+        });
+    }
+}
+
+/// Drops events nobody registered, so they don't pile up
+fn discard_unknown_events(mut received: ResMut<ReceivedEvents>) {
+    for (kind, pending) in received.0.drain() {
+        debug!("dropping {} events of unknown kind {}", pending.len(), kind);
+    }
+}
+
+pub trait NetworkEventAppExt {
+    /// Registers an event clients can send to the server
+    fn add_client_event<E: NetworkEvent>(&mut self) -> &mut Self;
+    /// Registers an event the server can send to clients
+    fn add_server_event<E: NetworkEvent>(&mut self) -> &mut Self;
+}
+
+impl NetworkEventAppExt for App {
+    fn add_client_event<E: NetworkEvent>(&mut self) -> &mut Self {
+        self.add_event::<FromClient<E>>().add_system_to_stage(
+            CoreStage::PreUpdate,
+            receive_client_events::<E>.after(PumpMessages),
+        )
+    }
+
+    fn add_server_event<E: NetworkEvent>(&mut self) -> &mut Self {
+        self.add_event::<ToClients<E>>().add_system_to_stage(
+            CoreStage::PostUpdate,
+            send_server_events::<E>.before(BroadcastMessages),
+        )
+    }
+}
+
+pub struct NetworkEventPlugin;
+
+impl Plugin for NetworkEventPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReceivedEvents>()
+            .add_system_to_stage(CoreStage::Last, discard_unknown_events);
+    }
+}
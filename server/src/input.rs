@@ -0,0 +1,110 @@
+//! Player inputs that steer their avatar
+//!
+//! Move inputs are checked before they are applied, and every applied input is acknowledged
+//! in the same batch as the avatar state that reflects it, so the client can reconcile its
+//! prediction. The new target takes effect from the next tick on, which is what the client
+//! predicts. What a click landed on is checked against the world as the player saw it, in the
+//! player's room.
+
+use crate::avatar::Avatar;
+use crate::lag_compensation::LagCompensation;
+use crate::visibility::ConnectionMappings;
+use crate::Broadcast;
+use bevy::prelude::*;
+use messages::{PlayerId, RoomId};
+use shared_components::movement::MoveTarget;
+
+/// Runs once the move inputs of this tick are applied
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct ApplyMoveInputs;
+
+/// A click-to-move input received from a connection
+#[derive(Debug, Clone)]
+pub struct MoveInput {
+    pub connection_id: u64,
+    pub target: Vec2,
+    pub sequence: u32,
+    pub view_tick: f64,
+}
+
+/// How close to an entity a click has to be to count as a hit
+const CLICK_RADIUS: f32 = 16.;
+
+/// Sequence number of the latest input applied to an avatar
+#[derive(Component)]
+pub struct LastInput(u32);
+
+fn apply_move_inputs(
+    mut commands: Commands,
+    mut inputs: EventReader<MoveInput>,
+    connections: Res<ConnectionMappings>,
+    mut avatars: Query<(Entity, &PlayerId, &RoomId, Option<&mut LastInput>), With<Avatar>>,
+    rooms: Query<&RoomId>,
+    mut broadcasts: EventWriter<Broadcast>,
+    lag_compensation: LagCompensation,
+) {
+    for input in inputs.iter() {
+        let player_id = match connections.player(input.connection_id) {
+            Some(player_id) => player_id,
+            None => continue,
+        };
+        let (avatar, _, room, last_input) = match avatars
+            .iter_mut()
+            .find(|(_, owner, _, _)| **owner == player_id)
+        {
+            Some(avatar) => avatar,
+            None => {
+                debug!("connection {} has no avatar to move", input.connection_id);
+                continue;
+            }
+        };
+
+        // targets outside the world are clamped like any other, see `clamp_move_targets`
+        if !input.target.is_finite() {
+            debug!(
+                "connection {}: rejected move to {:?}",
+                input.connection_id, input.target
+            );
+            continue;
+        }
+
+        // inputs arrive in order, anything older is a duplicate
+        match last_input {
+            Some(last) if last.0 >= input.sequence => continue,
+            Some(mut last) => last.0 = input.sequence,
+            None => {
+                commands.entity(avatar).insert(LastInput(input.sequence));
+            }
+        }
+
+        let clicked = lag_compensation
+            .entities_within(input.view_tick, input.target, CLICK_RADIUS)
+            .filter(|(entity, _)| *entity != avatar)
+            .filter(|(entity, _)| rooms.get(*entity).ok().map_or(true, |other| other == room))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+        if let Some((entity, distance)) = clicked {
+            debug!(
+                "connection {} clicked on {:?}, {:.1} away at tick {:.1}",
+                input.connection_id,
+                entity,
+                distance,
+                lag_compensation.rewind_tick(input.view_tick)
+            );
+        }
+
+        commands.entity(avatar).insert(MoveTarget(input.target));
+        broadcasts.send(Broadcast::InputAck {
+            connection_id: input.connection_id,
+            sequence: input.sequence,
+        });
+    }
+}
+
+pub struct InputPlugin;
+
+impl Plugin for InputPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<MoveInput>()
+            .add_system(apply_move_inputs.label(ApplyMoveInputs));
+    }
+}
@@ -0,0 +1,241 @@
+//! Rewinding replicated transforms to what a client saw
+//!
+//! Clients render other entities some time in the past (see the client's interpolation), so a
+//! click that hits an NPC on screen may miss it in the current server state. Every change of
+//! an [`NTransform`] is recorded in the entity's [`TransformHistory`], keyed by server tick, and
+//! [`LagCompensation`] answers spatial queries against the state at a client's view tick. The
+//! history is sampled the same way the client interpolates, and can't be rewound further than
+//! [`LagCompensationSettings::max_rewind`].
+
+use crate::tick::ServerTick;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use messages::{Tick, TICK_RATE};
+use shared_components::NTransform;
+use std::collections::VecDeque;
+
+pub struct LagCompensationSettings {
+    /// How far back queries can look, in seconds
+    pub max_rewind: f64,
+}
+
+impl Default for LagCompensationSettings {
+    fn default() -> Self {
+        LagCompensationSettings { max_rewind: 0.5 }
+    }
+}
+
+impl LagCompensationSettings {
+    fn max_rewind_ticks(&self) -> f64 {
+        (self.max_rewind * TICK_RATE).ceil()
+    }
+}
+
+/// Past transforms of an entity, oldest first, one entry per tick it changed in
+#[derive(Component, Default)]
+pub struct TransformHistory(VecDeque<(Tick, NTransform)>);
+
+impl TransformHistory {
+    fn record(&mut self, tick: Tick, transform: NTransform) {
+        if matches!(self.0.back(), Some((last, _)) if *last == tick) {
+            self.0.pop_back();
+        }
+        self.0.push_back((tick, transform));
+    }
+
+    /// Drops entries that are not needed to rewind to `oldest`
+    fn forget_before(&mut self, oldest: f64) {
+        while self.0.len() > 1 && self.0[1].0 as f64 <= oldest {
+            self.0.pop_front();
+        }
+    }
+
+    /// The transform at a (fractional) tick, between the recorded changes around it
+    pub fn at(&self, tick: f64) -> Option<NTransform> {
+        let after = self.0.iter().position(|(t, _)| *t as f64 > tick);
+        match after {
+            Some(0) => self.0.front().map(|(_, transform)| transform.clone()),
+            Some(i) => {
+                let (from_tick, from) = &self.0[i - 1];
+                let (to_tick, to) = &self.0[i];
+                let t = ((tick - *from_tick as f64) / (*to_tick - *from_tick) as f64) as f32;
+                Some(NTransform {
+                    translation: from.translation.lerp(to.translation, t),
+                    scale: from.scale.lerp(to.scale, t),
+                })
+            }
+            None => self.0.back().map(|(_, transform)| transform.clone()),
+        }
+    }
+}
+
+type Unrecorded = (With<NTransform>, Without<TransformHistory>);
+
+fn record_transform_history(
+    mut commands: Commands,
+    tick: Res<ServerTick>,
+    settings: Res<LagCompensationSettings>,
+    new: Query<(Entity, &NTransform), Unrecorded>,
+    mut changed: Query<(&NTransform, &mut TransformHistory), Changed<NTransform>>,
+) {
+    let oldest = tick.0 as f64 - settings.max_rewind_ticks();
+
+    for (n_transform, mut history) in changed.iter_mut() {
+        history.record(tick.0, n_transform.clone());
+        history.forget_before(oldest);
+    }
+
+    for (entity, n_transform) in new.iter() {
+        let mut history = TransformHistory::default();
+        history.record(tick.0, n_transform.clone());
+        commands.entity(entity).insert(history);
+    }
+}
+
+/// Spatial queries against the world as a client saw it
+#[derive(SystemParam)]
+pub struct LagCompensation<'w, 's> {
+    tick: Res<'w, ServerTick>,
+    settings: Res<'w, LagCompensationSettings>,
+    histories: Query<'w, 's, (Entity, &'static TransformHistory)>,
+}
+
+impl<'w, 's> LagCompensation<'w, 's> {
+    /// The tick a query for `view_tick` actually looks at, no further back than allowed
+    pub fn rewind_tick(&self, view_tick: f64) -> f64 {
+        let now = self.tick.0 as f64;
+        view_tick.clamp(now - self.settings.max_rewind_ticks(), now)
+    }
+
+    /// Entities within `radius` of `point` at `view_tick`, with their distance to it
+    pub fn entities_within(
+        &self,
+        view_tick: f64,
+        point: Vec2,
+        radius: f32,
+    ) -> impl Iterator<Item = (Entity, f32)> + '_ {
+        let tick = self.rewind_tick(view_tick);
+        self.histories.iter().filter_map(move |(entity, history)| {
+            let distance = history.at(tick)?.translation.distance(point);
+            (distance <= radius).then_some((entity, distance))
+        })
+    }
+}
+
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        // NTransforms are final once the update stage is done
+        app.init_resource::<LagCompensationSettings>()
+            .add_system_to_stage(CoreStage::PostUpdate, record_transform_history);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::SystemState;
+
+    fn at(x: f32) -> NTransform {
+        NTransform {
+            translation: Vec2::new(x, 0.),
+            scale: Vec2::ONE,
+        }
+    }
+
+    fn history(entries: &[(Tick, f32)]) -> TransformHistory {
+        let mut history = TransformHistory::default();
+        for (tick, x) in entries {
+            history.record(*tick, at(*x));
+        }
+        history
+    }
+
+    fn x_at(history: &TransformHistory, tick: f64) -> Option<f32> {
+        history.at(tick).map(|transform| transform.translation.x)
+    }
+
+    #[test]
+    fn history_interpolates_between_changes() {
+        let history = history(&[(10, 0.), (12, 20.), (13, 50.)]);
+        assert_eq!(x_at(&history, 10.), Some(0.));
+        assert_eq!(x_at(&history, 11.), Some(10.));
+        assert_eq!(x_at(&history, 11.5), Some(15.));
+        assert_eq!(x_at(&history, 12.), Some(20.));
+        assert_eq!(x_at(&history, 12.5), Some(35.));
+    }
+
+    #[test]
+    fn history_holds_its_ends() {
+        let history = history(&[(10, 0.), (12, 20.)]);
+        assert_eq!(x_at(&history, 3.), Some(0.));
+        assert_eq!(x_at(&history, 40.), Some(20.));
+        assert_eq!(x_at(&TransformHistory::default(), 10.), None);
+    }
+
+    #[test]
+    fn changes_in_the_same_tick_replace_each_other() {
+        let history = history(&[(10, 0.), (12, 20.), (12, 30.)]);
+        assert_eq!(history.0.len(), 2);
+        assert_eq!(x_at(&history, 12.), Some(30.));
+    }
+
+    #[test]
+    fn forgetting_keeps_what_rewinding_to_the_oldest_tick_needs() {
+        let mut history = history(&[(10, 0.), (12, 20.), (14, 40.), (16, 60.)]);
+        let before = x_at(&history, 13.);
+
+        history.forget_before(13.);
+        assert_eq!(history.0.front().map(|(tick, _)| *tick), Some(12));
+        assert_eq!(x_at(&history, 13.), before);
+
+        // the latest entry stays however far ahead the oldest tick is
+        history.forget_before(100.);
+        assert_eq!(history.0.len(), 1);
+        assert_eq!(x_at(&history, 100.), Some(60.));
+    }
+
+    fn world(now: Tick) -> World {
+        let mut world = World::new();
+        world.insert_resource(ServerTick(now));
+        world.insert_resource(LagCompensationSettings { max_rewind: 0.5 });
+        world
+    }
+
+    #[test]
+    fn rewinding_is_clamped_to_the_allowed_range() {
+        let mut world = world(100);
+        let mut state: SystemState<LagCompensation> = SystemState::new(&mut world);
+        let lag_compensation = state.get(&world);
+        let max_rewind = 0.5 * TICK_RATE;
+
+        assert_eq!(lag_compensation.rewind_tick(95.5), 95.5);
+        assert_eq!(lag_compensation.rewind_tick(0.), 100. - max_rewind);
+        assert_eq!(lag_compensation.rewind_tick(130.), 100.);
+    }
+
+    #[test]
+    fn queries_look_at_the_rewound_positions() {
+        let mut world = world(100);
+        let entity = world.spawn().insert(history(&[(95, 0.), (100, 100.)])).id();
+        let mut state: SystemState<LagCompensation> = SystemState::new(&mut world);
+        let lag_compensation = state.get(&world);
+
+        let hits: Vec<_> = lag_compensation
+            .entities_within(95., Vec2::new(5., 0.), 10.)
+            .collect();
+        assert_eq!(hits, vec![(entity, 5.)]);
+        assert_eq!(
+            lag_compensation
+                .entities_within(100., Vec2::new(5., 0.), 10.)
+                .count(),
+            0
+        );
+        // too far back, so the query looks at the oldest allowed tick instead
+        let hits: Vec<_> = lag_compensation
+            .entities_within(0., Vec2::new(5., 0.), 10.)
+            .collect();
+        assert_eq!(hits, vec![(entity, 5.)]);
+    }
+}
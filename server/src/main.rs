@@ -1,49 +1,104 @@
 //! Multiplayer webRTC server test with Bevy
+
+mod animation;
+mod avatar;
+mod chat;
+mod events;
+mod input;
+mod lag_compensation;
+mod network_id;
+mod npc;
+mod obstacle;
+mod resources;
+mod rooms;
+mod tick;
+mod tilemap;
+mod visibility;
+
 use std::any::TypeId;
 use std::io::Write;
 use std::net::TcpListener;
 
+use animation::{AnimationPlugin, ChooseAnimations};
 use async_tungstenite::accept_hdr_async;
 use async_tungstenite::tungstenite::handshake::client::Request;
 use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Response};
 use async_tungstenite::tungstenite::http::HeaderValue;
 use async_tungstenite::tungstenite::Message;
+use avatar::AvatarPlugin;
 use bevy::app::{PluginGroupBuilder, ScheduleRunnerPlugin};
-use bevy::asset::AssetPlugin;
-use bevy::audio::AudioPlugin;
+use bevy::asset::{AssetPlugin, AssetServerSettings};
 use bevy::core::CorePlugin;
-use bevy::core_pipeline::CorePipelinePlugin;
-use bevy::diagnostic::DiagnosticsPlugin;
-use bevy::ecs::query::WorldQuery;
-use bevy::gltf::GltfPlugin;
-use bevy::input::InputPlugin;
 use bevy::log::{LogPlugin, LogSettings};
-use bevy::pbr::PbrPlugin;
-use bevy::reflect::serde::ReflectSerializer;
-use bevy::reflect::{FromType, TypeRegistry};
-use bevy::render::RenderPlugin;
-use bevy::scene::ScenePlugin;
-use bevy::sprite::SpritePlugin;
-use bevy::tasks::{IoTaskPool, TaskPoolBuilder};
-use bevy::text::TextPlugin;
+use bevy::tasks::IoTaskPool;
 use bevy::time::TimePlugin;
-use bevy::ui::UiPlugin;
-use bevy::utils::HashMap;
-use bevy::window::WindowPlugin;
-use bevy::winit::WinitPlugin;
+use bevy::utils::{HashMap, HashSet};
 use bevy::{app::ScheduleRunnerSettings, prelude::*, utils::Duration};
+use chat::ChatPlugin;
+use events::{
+    FromClient, NetworkEventAppExt, NetworkEventPlugin, ReceivedEvents, Recipients, ToClients,
+};
 use futures::prelude::*;
 use futures_util::{StreamExt, TryStreamExt};
-use messages::{PlayerMessage, ServerMessage};
+use input::{InputPlugin, MoveInput};
+use lag_compensation::LagCompensationPlugin;
+use messages::{
+    Delivery, KindId, NetworkEntity, NetworkEntityMap, PlayerId, PlayerMessage, RoomId,
+    ServerMessage,
+};
+use network_id::NetworkIdPlugin;
+use npc::NpcPlugin;
+use obstacle::ObstaclePlugin;
+use resources::NetworkResourceAppExt;
+use rooms::{RoomAction, RoomChanged, RoomPlugin, RoomRequest, Rooms};
 use serde::Serialize;
-use shared_components::{NSprite, NTransform};
-
-type ServerMessageSenders = HashMap<u64, futures::channel::mpsc::Sender<messages::ServerMessage>>;
+use shared_components::collision::{clamp_move_targets, resolve_collisions};
+use shared_components::entity_mapping::{map_entities, wire_entity};
+use shared_components::movement::{move_to_targets, MovementSettings};
+use shared_components::navigation::{update_nav_grid, NavGrid};
+use shared_components::spatial::{update_spatial_grid, SpatialGrid};
+use shared_components::{NParent, NTransform, PlayEffect, Tilemap, UseAbility, WorldSettings};
+use tick::{ServerTick, TickPlugin};
+use tilemap::TilemapPlugin;
+use visibility::{CommitVisibility, ConnectionMappings, NetworkVisibility, VisibilityPlugin};
+
+type ServerMessageSenders = HashMap<u64, futures::channel::mpsc::Sender<Outgoing>>;
+/// Connections that haven't said hello yet, they don't get any broadcasts
+#[derive(Default, Deref, DerefMut)]
+struct PendingConnections(HashMap<u64, futures::channel::mpsc::Sender<Outgoing>>);
 type PlayerMessageReceiver = futures::channel::mpsc::Receiver<(u64, messages::PlayerMessage)>;
 type PlayerMessageSender = futures::channel::mpsc::Sender<(u64, messages::PlayerMessage)>;
-type ConnectionMappings = HashMap<u64, messages::PlayerId>;
 
-struct WsServer {}
+/// A message queued for a connection, along with how it should be delivered
+#[derive(Debug)]
+struct Outgoing {
+    delivery: Delivery,
+    message: ServerMessage,
+}
+
+/// Drops unreliable component updates that are superseded by a later one in the same batch
+///
+/// Websockets only give us a reliable ordered stream, so this is the best we can do for
+/// unreliable delivery: when the socket falls behind, only the latest state is written.
+fn drop_stale_updates(pending: Vec<Outgoing>) -> Vec<ServerMessage> {
+    let mut seen = HashSet::new();
+    let mut messages: Vec<ServerMessage> = pending
+        .into_iter()
+        .rev()
+        .filter(|outgoing| match (&outgoing.delivery, &outgoing.message) {
+            (
+                Delivery::Unreliable,
+                ServerMessage::ComponentChanged {
+                    entity, component, ..
+                },
+            ) => seen.insert((*entity, *component)),
+            _ => true,
+        })
+        .map(|outgoing| outgoing.message)
+        .collect();
+    messages.reverse();
+    messages
+}
 
 struct ServerSettings {
     ip_address: String,
@@ -59,16 +114,37 @@ impl Default for ServerSettings {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct PumpMessages;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct BroadcastMessages;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+struct Movement;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+struct Collisions;
+
 struct NetworkPlugin;
 
 impl Plugin for NetworkPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<Broadcast>()
+        app.add_plugin(NetworkIdPlugin)
+            .add_plugin(NetworkEventPlugin)
+            .add_plugin(VisibilityPlugin)
+            .add_plugin(TickPlugin)
+            .add_plugin(InputPlugin)
+            .add_plugin(LagCompensationPlugin)
+            .add_event::<Broadcast>()
             .add_event::<PlayerEvent>()
             .add_startup_system(start_websocket_server)
             .add_system(accept_websocket_connections)
-            .add_system(pump_messages)
-            .add_system_to_stage(CoreStage::PostUpdate, broadcast_messages);
+            .add_system_to_stage(CoreStage::PreUpdate, pump_messages.label(PumpMessages))
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                broadcast_messages.label(BroadcastMessages),
+            );
     }
 }
 
@@ -76,71 +152,300 @@ struct MyPlugins;
 
 impl PluginGroup for MyPlugins {
     fn build(&mut self, group: &mut PluginGroupBuilder) {
-        group.add(LogPlugin::default());
-        group.add(CorePlugin::default());
-        group.add(TimePlugin::default());
-        group.add(TransformPlugin::default());
+        group.add(LogPlugin);
+        group.add(CorePlugin);
+        group.add(TimePlugin);
+        group.add(TransformPlugin);
 
-        group.add(AssetPlugin::default());
-        group.add(ScheduleRunnerPlugin::default());
+        group.add(AssetPlugin);
+        group.add(ScheduleRunnerPlugin);
         group.add(NetworkPlugin);
     }
 }
 
 #[derive(Clone, Debug)]
 enum PlayerEvent {
-    PlayerJoined,
-    PlayerLeft,
+    PlayerJoined {
+        connection_id: u64,
+    },
+    PlayerLeft {
+        connection_id: u64,
+        player_id: PlayerId,
+    },
 }
 
 #[derive(Clone, Debug)]
-enum Broadcast {
+pub enum Broadcast {
     ComponentChanged {
-        entity: Entity,
+        entity: NetworkEntity,
         component: u16,
+        delivery: Delivery,
         data: Vec<u8>,
+        recipients: Recipients,
     },
+    /// Added components of an entity that was spawned (or joined the recipients' view) this
+    /// frame are bundled into one [`ServerMessage::EntitySpawned`] per entity
     ComponentAdded {
-        entity: Entity,
+        entity: NetworkEntity,
         component: u16,
         data: Vec<u8>,
+        spawn: bool,
+        recipients: Recipients,
+    },
+    ComponentRemoved {
+        entity: NetworkEntity,
+        component: u16,
+        recipients: Recipients,
     },
+    EntityDespawned {
+        entity: NetworkEntity,
+        recipients: Recipients,
+    },
+    ResourceChanged {
+        recipients: Recipients,
+        kind: u16,
+        data: Vec<u8>,
+    },
+    InputAck {
+        connection_id: u64,
+        sequence: u32,
+    },
+    Event {
+        recipients: Recipients,
+        kind: u16,
+        data: Vec<u8>,
+    },
+    /// A message for one connection only, like the answer to a request
+    Reply {
+        connection_id: u64,
+        message: ServerMessage,
+    },
+}
+
+impl Broadcast {
+    fn is_for(&self, connection_id: u64) -> bool {
+        match self {
+            Broadcast::ComponentChanged { recipients, .. }
+            | Broadcast::ComponentAdded { recipients, .. }
+            | Broadcast::ComponentRemoved { recipients, .. }
+            | Broadcast::EntityDespawned { recipients, .. }
+            | Broadcast::ResourceChanged { recipients, .. }
+            | Broadcast::Event { recipients, .. } => recipients.contains(connection_id),
+            Broadcast::InputAck {
+                connection_id: id, ..
+            }
+            | Broadcast::Reply {
+                connection_id: id, ..
+            } => *id == connection_id,
+        }
+    }
+
+    fn to_outgoing(&self) -> Outgoing {
+        let (delivery, message) = match self {
+            Broadcast::ComponentChanged {
+                entity,
+                component,
+                delivery,
+                data,
+                ..
+            } => (
+                *delivery,
+                ServerMessage::ComponentChanged {
+                    entity: *entity,
+                    component: *component,
+                    data: data.clone(),
+                },
+            ),
+            Broadcast::ComponentAdded {
+                entity,
+                component,
+                data,
+                ..
+            } => (
+                Delivery::Reliable,
+                ServerMessage::ComponentAdded {
+                    entity: *entity,
+                    component: *component,
+                    data: data.clone(),
+                },
+            ),
+            Broadcast::ComponentRemoved {
+                entity, component, ..
+            } => (
+                Delivery::Reliable,
+                ServerMessage::ComponentRemoved {
+                    entity: *entity,
+                    component: *component,
+                },
+            ),
+            Broadcast::EntityDespawned { entity, .. } => (
+                Delivery::Reliable,
+                ServerMessage::EntityDespawned { entity: *entity },
+            ),
+            Broadcast::ResourceChanged { kind, data, .. } => (
+                Delivery::Reliable,
+                ServerMessage::ResourceChanged {
+                    kind: *kind,
+                    data: data.clone(),
+                },
+            ),
+            Broadcast::InputAck { sequence, .. } => (
+                Delivery::Reliable,
+                ServerMessage::InputAck {
+                    sequence: *sequence,
+                },
+            ),
+            Broadcast::Event { kind, data, .. } => (
+                Delivery::Reliable,
+                ServerMessage::Event {
+                    kind: *kind,
+                    data: data.clone(),
+                },
+            ),
+            Broadcast::Reply { message, .. } => (Delivery::Reliable, message.clone()),
+        };
+
+        Outgoing { delivery, message }
+    }
 }
 
-fn networked<T: Component + Serialize + Reflect>(
-    query: Query<(Entity, &T, ChangeTrackers<T>)>,
+/// A networked component with everything needed to decide who gets it
+type NetworkedItem<'a, T> = (
+    &'a NetworkEntity,
+    ChangeTrackers<NetworkEntity>,
+    &'a T,
+    ChangeTrackers<T>,
+    Option<&'a PlayerId>,
+    Option<&'a NetworkVisibility>,
+    Option<&'a RoomId>,
+);
+
+#[allow(clippy::too_many_arguments)]
+fn networked<T: Component + Serialize + Reflect + Clone + KindId>(
+    query: Query<NetworkedItem<T>>,
     type_mappings: Res<(HashMap<u16, TypeId>, HashMap<TypeId, u16>)>,
-    type_registry: Res<TypeRegistry>,
+    network_entities: Res<NetworkEntityMap>,
+    connections: Res<ConnectionMappings>,
+    rooms: Res<Rooms>,
+    mut player_events: EventReader<PlayerEvent>,
+    mut room_changes: EventReader<RoomChanged>,
     mut broadcasts: ResMut<Events<Broadcast>>,
 ) {
-    for (entity, component, tracker) in query.iter() {
-        if tracker.is_added() || tracker.is_changed() {
-            // let read_register = type_registry.read();
-            // let reflect_serializer = ReflectSerializer::new(component, &*read_register);
-            // let mut buffer = Vec::new();
-            // let mut serializer = rmp_serde::Serializer::new(&mut buffer)
-            //     .with_binary()
-            //     .with_struct_tuple();
-            //
-            // reflect_serializer.serialize(&mut serializer).unwrap();
-            // let data = buffer;
-            // let data = rmp_serde::to_vec(&component).unwrap();
-            let data = postcard::to_allocvec(&component).unwrap();
+    // players that just joined get everything as a snapshot, and players that just moved to
+    // another room get everything in it
+    let joined: Vec<u64> = player_events
+        .iter()
+        .filter_map(|event| match event {
+            PlayerEvent::PlayerJoined { connection_id } => Some(*connection_id),
+            _ => None,
+        })
+        .collect();
+    let entered_rooms: Vec<(u64, RoomId)> = room_changes
+        .iter()
+        .map(|change| (change.connection_id, change.to))
+        .collect();
+    let kind = *type_mappings.1.get(&std::any::TypeId::of::<T>()).unwrap();
+    let filtered = T::VISIBILITY == messages::VisibilityRule::Filtered;
+
+    for (entity, id_tracker, component, tracker, owner, visibility, room) in query.iter() {
+        // an entity that just got its id is new to everyone, whatever its components say
+        let spawned = id_tracker.is_added();
+        let added = spawned || tracker.is_added();
+        let (entered, left): (Vec<u64>, Vec<u64>) = match visibility {
+            Some(visibility) if filtered && !added => {
+                (visibility.entered().collect(), visibility.left().collect())
+            }
+            _ => Default::default(),
+        };
+
+        if !left.is_empty() {
+            broadcasts.send(Broadcast::ComponentRemoved {
+                entity: *entity,
+                component: kind,
+                recipients: Recipients::Group(left),
+            });
+        }
+        let newcomers: Vec<u64> = match room {
+            Some(room) => entered_rooms
+                .iter()
+                .filter(|(_, to)| to == room)
+                .map(|(connection_id, _)| *connection_id)
+                .collect(),
+            None => joined.clone(),
+        };
+        if !added && !tracker.is_changed() && newcomers.is_empty() && entered.is_empty() {
+            continue;
+        }
+
+        let recipients = visibility::recipients(T::VISIBILITY, owner, visibility, &connections);
+        let recipients = rooms.scope(room, recipients);
 
+        // entity references go over the wire as network ids
+        let mut component = component.clone();
+        if let Err(e) = map_entities(&mut component, &mut |e| {
+            network_entities.get_network(&e).map(wire_entity)
+        }) {
+            warn!("not sending component {} of {}: {}", kind, entity, e);
+            continue;
+        }
+
+        // let data = rmp_serde::to_vec(&component).unwrap();
+        let data = postcard::to_allocvec(&component).unwrap();
+
+        // snapshot goes first, so joining players never see updates before the spawn
+        let snapshot: Vec<u64> = newcomers
+            .into_iter()
+            .filter(|connection_id| recipients.contains(*connection_id))
+            .collect();
+        // connections that can newly see the component already have the entity
+        let entered: Vec<u64> = entered
+            .into_iter()
+            .filter(|connection_id| !snapshot.contains(connection_id))
+            .collect();
+        if !snapshot.is_empty() {
+            broadcasts.send(Broadcast::ComponentAdded {
+                entity: *entity,
+                component: kind,
+                data: data.clone(),
+                spawn: true,
+                recipients: Recipients::Group(snapshot),
+            });
+        }
+        if !entered.is_empty() {
+            broadcasts.send(Broadcast::ComponentAdded {
+                entity: *entity,
+                component: kind,
+                data: data.clone(),
+                spawn: false,
+                recipients: Recipients::Group(entered),
+            });
+        }
+
+        if added {
+            broadcasts.send(Broadcast::ComponentAdded {
+                entity: *entity,
+                component: kind,
+                data,
+                spawn: spawned,
+                recipients,
+            });
+        } else if tracker.is_changed() {
             broadcasts.send(Broadcast::ComponentChanged {
-                entity,
-                component: *type_mappings.1.get(&std::any::TypeId::of::<T>()).unwrap(),
+                entity: *entity,
+                component: kind,
+                delivery: T::DELIVERY,
                 data,
+                recipients,
             });
         }
     }
 }
 
 fn main() {
-    let mut options = DefaultTaskPoolOptions::with_num_threads(16);
+    let options = DefaultTaskPoolOptions::with_num_threads(16);
     App::new()
         .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
-            1.0 / 30.0,
+            1.0 / messages::TICK_RATE,
         )))
         .insert_resource(shared_components::kind_to_type_id_mappings())
         .insert_resource(ServerSettings::default())
@@ -149,17 +454,83 @@ fn main() {
             level: bevy::log::Level::DEBUG,
         })
         .insert_resource(options)
+        // the assets are shared with the client, relative to this crate when run with cargo
+        .insert_resource(AssetServerSettings {
+            asset_folder: "../assets".into(),
+            ..Default::default()
+        })
         .add_plugins(MyPlugins)
         .register_type::<shared_components::NTransform>()
-        .add_system(networked::<shared_components::NTransform>)
-        .register_type::<shared_components::NSprite>()
-        .add_system(networked::<shared_components::NSprite>)
-        .add_startup_system(spawn_npcs)
-        .add_system(translate_transform)
-        .add_system(move_entities)
-        .add_system(animate_sprite)
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            networked::<shared_components::NTransform>
+                .before(CommitVisibility)
+                .before(BroadcastMessages),
+        )
+        .register_type::<shared_components::NParent>()
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            networked::<shared_components::NParent>
+                .before(CommitVisibility)
+                .before(BroadcastMessages),
+        )
+        .register_type::<shared_components::NControlled>()
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            networked::<shared_components::NControlled>
+                .before(CommitVisibility)
+                .before(BroadcastMessages),
+        )
+        .register_type::<shared_components::NAnimation>()
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            networked::<shared_components::NAnimation>
+                .after(ChooseAnimations)
+                .before(CommitVisibility)
+                .before(BroadcastMessages),
+        )
+        .register_type::<shared_components::NCollider>()
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            networked::<shared_components::NCollider>
+                .before(CommitVisibility)
+                .before(BroadcastMessages),
+        )
+        .register_type::<shared_components::NObstacle>()
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            networked::<shared_components::NObstacle>
+                .before(CommitVisibility)
+                .before(BroadcastMessages),
+        )
+        .insert_resource(WorldSettings {
+            size: Vec2::new(1200., 600.),
+        })
+        .add_networked_resource::<WorldSettings>()
+        .add_plugin(TilemapPlugin)
+        .add_networked_resource::<Tilemap>()
+        .add_client_event::<UseAbility>()
+        .add_server_event::<PlayEffect>()
+        .add_plugin(AvatarPlugin)
+        .add_system(use_abilities)
+        .add_plugin(NpcPlugin)
+        .add_plugin(ObstaclePlugin)
+        .add_plugin(AnimationPlugin)
+        .add_plugin(RoomPlugin)
+        .add_plugin(ChatPlugin)
+        // after movement, so updates carry this tick's positions
+        .add_system(translate_transform.after(Collisions))
+        .add_system(translate_parent)
+        .init_resource::<MovementSettings>()
+        .add_system(clamp_move_targets.before(Movement))
+        .add_system(move_to_targets.label(Movement))
+        .init_resource::<NavGrid>()
+        .add_system(update_nav_grid.before(Movement))
+        .add_system(resolve_collisions.label(Collisions).after(Movement))
+        .init_resource::<SpatialGrid>()
+        // once everything has moved, spawned or despawned this tick
+        .add_system_to_stage(CoreStage::Last, update_spatial_grid)
         .add_system(counter)
-        // .add_system(get_new_target)
         .run();
 }
 
@@ -170,7 +541,9 @@ fn start_websocket_server(mut commands: Commands, settings: Res<ServerSettings>)
 
     debug!("starting tcp listener at {}", &settings.ip_address);
     let server = TcpListener::bind(&settings.ip_address).expect("Failed to start server");
-    server.set_nonblocking(true);
+    server
+        .set_nonblocking(true)
+        .expect("Failed to set listener to non-blocking mode");
 
     let io_pool = IoTaskPool::get();
     io_pool
@@ -178,12 +551,12 @@ fn start_websocket_server(mut commands: Commands, settings: Res<ServerSettings>)
             debug!("debug from future 1");
             println!("println from future 1");
             std::io::stdout().flush().unwrap();
-            future::ok::<(), ()>(())
         })
         .detach();
 
     commands.insert_resource(server);
     commands.insert_resource(server_message_senders);
+    commands.init_resource::<PendingConnections>();
     commands.insert_resource(player_message_sender);
     commands.insert_resource(player_message_receiver);
 
@@ -191,6 +564,7 @@ fn start_websocket_server(mut commands: Commands, settings: Res<ServerSettings>)
     debug!("io threads: {}", io.thread_num());
 }
 
+#[allow(clippy::result_large_err)] // signature is dictated by tungstenite's handshake callback
 fn add_csp(_request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
     response.headers_mut().insert(
         "Content-Security-Policy",
@@ -202,7 +576,7 @@ fn add_csp(_request: &Request, mut response: Response) -> Result<Response, Error
 fn accept_websocket_connections(
     server_settings: Res<ServerSettings>,
     server: Res<TcpListener>,
-    mut server_message_senders: ResMut<ServerMessageSenders>,
+    mut pending_connections: ResMut<PendingConnections>,
     player_message_sender: Res<PlayerMessageSender>,
     mut next_connection_id: Local<u64>,
 ) {
@@ -218,7 +592,7 @@ fn accept_websocket_connections(
             futures::channel::mpsc::channel(server_settings.channel_size);
         let player_message_sender = player_message_sender.clone();
 
-        server_message_senders.insert(connection_id, server_message_sender);
+        pending_connections.insert(connection_id, server_message_sender);
 
         debug!("spawning io task for connection {}", connection_id);
 
@@ -244,27 +618,53 @@ fn accept_websocket_connections(
 
                 futures::select! {
                     send = next_send => {
-                        // let data = rmp_serde::to_vec(&send).unwrap();
-                        let data = postcard::to_allocvec(&send).unwrap();
-                        debug!("encoded length: {}", data.len());
-                        ws_write.send(Message::Binary(data[1..].to_vec())).await;
+                        let mut pending = match send {
+                            Some(send) => vec![send],
+                            None => {
+                                debug!("connection {}: server dropped the connection, exiting io task", connection_id);
+                                break
+                            }
+                        };
+                        // grab everything else that piled up while we were busy
+                        while let Ok(Some(send)) = server_message_receiver.try_next() {
+                            pending.push(send);
+                        }
+
+                        for message in drop_stale_updates(pending) {
+                            // let data = rmp_serde::to_vec(&message).unwrap();
+                            let data = postcard::to_allocvec(&message).unwrap();
+                            debug!("encoded length: {}", data.len());
+                            if ws_write.send(Message::Binary(data)).await.is_err() {
+                                debug!("connection {}: failed to write to websocket, exiting io task", connection_id);
+                                return
+                            }
+                        }
                     },
                     msg = next_message => {
                         if let Some(msg) = msg {
-                            let data = msg.unwrap().into_data();
+                            // dropping the receiver below tells the ECS the player left
+                            let data = match msg {
+                                Ok(msg) => msg.into_data(),
+                                Err(_) => {
+                                    debug!("connection {}: failed to read from websocket, exiting io task", connection_id);
+                                    break
+                                }
+                            };
                             println!("data: {:02X?}", &data);
                             // let message: PlayerMessage =
                             //     rmp_serde::from_read_ref(&data).expect("failed to parse player message");
-                            let message: PlayerMessage =
-                                postcard::from_bytes(&data).expect("failed to parse player message");
-
-                            match player_message_sender.try_send((connection_id, message)) {
-                                Ok(_) => future::ok(()),
-                                Err(_) => {
-                                    debug!("failed to add player message to channel, exiting future");
-                                    future::err(())
+                            let message: PlayerMessage = match postcard::from_bytes(&data) {
+                                Ok(message) => message,
+                                Err(e) => {
+                                    debug!("connection {}: failed to parse player message: {}, exiting io task", connection_id, e);
+                                    break
                                 }
-                            }.await;
+                            };
+
+                            if player_message_sender.try_send((connection_id, message)).is_err() {
+                                debug!("failed to add player message to channel, exiting future");
+                                break
+                            }
                         } else {
                             debug!("connection {}: websocket returned None, exiting io task", connection_id);
                             break
@@ -276,86 +676,246 @@ fn accept_websocket_connections(
     }
 }
 
-fn pump_messages(mut receive: ResMut<PlayerMessageReceiver>) {
+#[allow(clippy::too_many_arguments)]
+fn pump_messages(
+    mut receive: ResMut<PlayerMessageReceiver>,
+    mut received_events: ResMut<ReceivedEvents>,
+    mut pending_connections: ResMut<PendingConnections>,
+    mut senders: ResMut<ServerMessageSenders>,
+    mut connections: ResMut<ConnectionMappings>,
+    mut player_events: EventWriter<PlayerEvent>,
+    mut move_inputs: EventWriter<MoveInput>,
+    mut room_requests: EventWriter<RoomRequest>,
+    tick: Res<ServerTick>,
+) {
     while let Ok(Some((connection_id, message))) = receive.try_next() {
         println!("got a message from {}: {:?}", connection_id, message);
+
+        match message {
+            PlayerMessage::Hello { my_id } => {
+                // a player joins once we know who they are, so owner-only components can
+                // be part of their snapshot
+                if let Some(sender) = pending_connections.remove(&connection_id) {
+                    connections.insert(connection_id, my_id);
+                    senders.insert(connection_id, sender);
+                    player_events.send(PlayerEvent::PlayerJoined { connection_id });
+                }
+            }
+            PlayerMessage::Event { kind, data } => {
+                received_events.push(connection_id, kind, data);
+            }
+            PlayerMessage::MoveTo {
+                target,
+                sequence,
+                view_tick,
+            } => move_inputs.send(MoveInput {
+                connection_id,
+                target,
+                sequence,
+                view_tick,
+            }),
+            PlayerMessage::Ping { sent_at } => {
+                let pong = Outgoing {
+                    delivery: Delivery::Reliable,
+                    message: ServerMessage::Pong {
+                        sent_at,
+                        tick: tick.0,
+                    },
+                };
+                if let Some(sender) = senders.get_mut(&connection_id) {
+                    // a full channel is dealt with when broadcasting, the client pings again
+                    let _ = sender.try_send(pong);
+                }
+            }
+            PlayerMessage::CreateRoom { name } => room_requests.send(RoomRequest {
+                connection_id,
+                action: RoomAction::Create { name },
+            }),
+            PlayerMessage::ListRooms => room_requests.send(RoomRequest {
+                connection_id,
+                action: RoomAction::List,
+            }),
+            PlayerMessage::JoinRoom { room } => room_requests.send(RoomRequest {
+                connection_id,
+                action: RoomAction::Join { room },
+            }),
+            PlayerMessage::LeaveRoom => room_requests.send(RoomRequest {
+                connection_id,
+                action: RoomAction::Leave,
+            }),
+        }
     }
 }
 
 fn broadcast_messages(
     mut senders: ResMut<ServerMessageSenders>,
+    mut pending_connections: ResMut<PendingConnections>,
+    mut connections: ResMut<ConnectionMappings>,
     mut broadcasts: EventReader<Broadcast>,
+    mut player_events: EventWriter<PlayerEvent>,
+    tick: Res<ServerTick>,
 ) {
+    let mut outgoing: HashMap<u64, Vec<Outgoing>> = HashMap::new();
+    // where the spawn bundle of an entity is in a connection's queue
+    let mut spawns: HashMap<(u64, NetworkEntity), usize> = HashMap::new();
+
     for b in broadcasts.iter() {
-        let b: &Broadcast = b;
-
-        for (conn_id, sender) in senders.iter_mut() {
-            let sender: &mut futures::channel::mpsc::Sender<ServerMessage> = sender;
-            let msg = match b {
-                Broadcast::ComponentChanged {
-                    entity,
-                    component,
-                    data,
-                } => ServerMessage::ComponentChanged {
-                    entity: entity.into(),
-                    component: component.clone(),
-                    data: data.clone(),
-                },
-                Broadcast::ComponentAdded {
-                    entity,
-                    component,
-                    data,
-                } => ServerMessage::ComponentAdded {
-                    entity: entity.into(),
-                    component: component.clone(),
-                    data: data.clone(),
-                },
-            };
-            sender
-                .try_send(msg)
-                .expect("failed to send broadcast message");
+        for conn_id in senders.keys() {
+            if !b.is_for(*conn_id) {
+                continue;
+            }
+            let queue = outgoing.entry(*conn_id).or_default();
+
+            if let Broadcast::ComponentAdded {
+                entity,
+                component,
+                data,
+                spawn: true,
+                ..
+            } = b
+            {
+                let index = *spawns.entry((*conn_id, *entity)).or_insert_with(|| {
+                    queue.push(Outgoing {
+                        delivery: Delivery::Reliable,
+                        message: ServerMessage::EntitySpawned {
+                            entity: *entity,
+                            components: Vec::new(),
+                        },
+                    });
+                    queue.len() - 1
+                });
+                if let ServerMessage::EntitySpawned { components, .. } = &mut queue[index].message {
+                    components.retain(|(kind, _)| kind != component);
+                    components.push((*component, data.clone()));
+                }
+            } else {
+                queue.push(b.to_outgoing());
+            }
         }
     }
+
+    // every batch starts with the tick it was sent at
+    for queue in outgoing.values_mut() {
+        queue.insert(
+            0,
+            Outgoing {
+                delivery: Delivery::Reliable,
+                message: ServerMessage::Tick { tick: tick.0 },
+            },
+        );
+    }
+
+    senders.retain(|conn_id, sender| {
+        if sender.is_closed() {
+            debug!("connection {} closed, dropping it", conn_id);
+            return false;
+        }
+        for message in outgoing.remove(conn_id).unwrap_or_default() {
+            let delivery = message.delivery;
+            match sender.try_send(message) {
+                Ok(_) => {}
+                Err(e) if e.is_disconnected() => {
+                    debug!("connection {} closed, dropping it", conn_id);
+                    return false;
+                }
+                Err(_) if delivery == Delivery::Unreliable => {}
+                Err(_) => {
+                    warn!(
+                        "connection {} can't keep up with reliable messages, dropping it",
+                        conn_id
+                    );
+                    return false;
+                }
+            }
+        }
+        true
+    });
+    connections.retain(|connection_id, player_id| {
+        let connected = senders.contains_key(&connection_id);
+        if !connected {
+            player_events.send(PlayerEvent::PlayerLeft {
+                connection_id,
+                player_id,
+            });
+        }
+        connected
+    });
+    pending_connections.retain(|_, sender| !sender.is_closed());
 }
 
-#[derive(Component)]
-struct Npc;
-
-#[derive(Component, Deref, DerefMut)]
-struct AnimationTimer(Timer);
-#[derive(Component)]
-struct MoveTarget(Vec3);
-
-fn spawn_npcs(mut commnads: Commands) {
-    for _ in 0..50 {
-        let random_x = rand::random::<f32>() * 1200. - 600.;
-        let random_y = rand::random::<f32>() * 600. - 300.;
-
-        commnads
-            .spawn_bundle(TransformBundle::default())
-            .insert(NSprite::default())
-            .insert(NTransform::default())
-            .insert(Npc)
-            .insert(AnimationTimer(Timer::from_seconds(0.1, true)))
-            .insert(MoveTarget(Vec3::new(random_x, random_y, 0.)));
+/// Collider radius of the creatures players and NPCs are
+const CREATURE_RADIUS: f32 = 8.;
+
+/// Gives up on finding a clear spot after this many tries and takes the last one
+const SPAWN_TRIES: usize = 100;
+
+/// A random point in the playable area where a circle of `radius` is clear of solid tiles
+fn random_position(settings: &WorldSettings, map: Option<&Tilemap>, radius: f32) -> Vec2 {
+    let bounds = (settings.size - 2. * radius).max(Vec2::ZERO);
+    let mut position = Vec2::ZERO;
+    for _ in 0..SPAWN_TRIES {
+        let unit = Vec2::new(rand::random::<f32>(), rand::random::<f32>());
+        position = (unit - 0.5) * bounds;
+        if map.map_or(true, |map| map.is_clear(position, radius)) {
+            return position;
+        }
     }
+    warn!("no clear spot found for a radius of {}", radius);
+    position
 }
 
 fn translate_transform(mut entities: Query<(&Transform, &mut NTransform), Changed<Transform>>) {
     for (t, mut nt) in entities.iter_mut() {
-        *nt = NTransform::from(t.clone())
+        *nt = NTransform::from(*t)
     }
 }
 
-fn animate_sprite(time: Res<Time>, mut query: Query<(&mut AnimationTimer, &mut NSprite)>) {
-    for (mut timer, mut sprite) in &mut query {
-        timer.tick(time.delta());
-        if timer.just_finished() {
-            sprite.sprite_index = (sprite.sprite_index + 1) % 4;
+fn translate_parent(
+    mut commands: Commands,
+    parents: Query<(Entity, &Parent, Option<&NParent>), Changed<Parent>>,
+    mut orphans: Query<&mut NParent, Without<Parent>>,
+) {
+    for (entity, parent, n_parent) in parents.iter() {
+        if n_parent.and_then(|np| np.parent) != Some(parent.get()) {
+            commands.entity(entity).insert(NParent {
+                parent: Some(parent.get()),
+            });
+        }
+    }
+
+    for mut n_parent in orphans.iter_mut() {
+        if n_parent.parent.is_some() {
+            n_parent.parent = None;
         }
     }
 }
 
+/// Plays the effect of an ability for everyone in the room to see
+fn use_abilities(
+    mut used: EventReader<FromClient<UseAbility>>,
+    rooms: Res<Rooms>,
+    mut effects: EventWriter<ToClients<PlayEffect>>,
+) {
+    for FromClient {
+        connection_id,
+        event,
+    } in used.iter()
+    {
+        debug!(
+            "connection {} used ability {} on {:?}",
+            connection_id, event.ability, event.target
+        );
+        let room = rooms.room_of(*connection_id);
+        effects.send(ToClients {
+            recipients: rooms.scope(room.as_ref(), Recipients::Everyone),
+            event: PlayEffect {
+                effect: event.ability,
+                entity: event.target,
+            },
+        });
+    }
+}
+
 fn counter(mut state: Local<CounterState>) {
     if state.count % 60 == 0 {
         println!("{}", state.count);
@@ -367,30 +927,3 @@ fn counter(mut state: Local<CounterState>) {
 struct CounterState {
     count: u32,
 }
-
-fn move_entities(
-    mut entities: Query<(Entity, &mut Transform, &MoveTarget)>,
-    mut commands: Commands,
-) {
-    for (entity, mut transform, target) in entities.iter_mut() {
-        let distance = transform.translation.distance(target.0).min(1.0);
-        let direction = (target.0 - transform.translation).normalize_or_zero();
-        transform.translation += direction * distance;
-
-        if distance <= 0.01 {
-            commands.entity(entity).remove::<MoveTarget>();
-            println!("entity reached target!");
-        }
-    }
-}
-
-fn get_new_target(mut commands: Commands, entities: Query<(Entity,), Without<MoveTarget>>) {
-    for (entity,) in entities.iter() {
-        let random_x = rand::random::<f32>() * 1200. - 600.;
-        let random_y = rand::random::<f32>() * 600. - 300.;
-
-        commands
-            .entity(entity)
-            .insert(MoveTarget(Vec3::new(random_x, random_y, 0.)));
-    }
-}
@@ -0,0 +1,91 @@
+//! Network id allocation for replicated entities
+//!
+//! Entities tagged with [`Replicated`] get a [`NetworkEntity`] id from the
+//! [`NetworkIdAllocator`] right after the update stage, so the replication systems in
+//! `PostUpdate` always see an id on anything spawned this frame. Despawned entities are
+//! broadcast to clients so they can drop their copies, in the batch of the next frame: they are
+//! collected in a stage of their own after [`CoreStage::Last`], so despawns from any stage are
+//! seen before the removals are cleared at the end of the frame.
+
+use crate::events::Recipients;
+use crate::rooms::Rooms;
+use crate::Broadcast;
+use bevy::prelude::*;
+use messages::{NetworkEntity, NetworkEntityMap};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub struct AssignNetworkIds;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub struct ForgetNetworkIds;
+
+/// Marks an entity for replication to clients
+#[derive(Component, Default)]
+pub struct Replicated;
+
+/// Hands out network ids in increasing order, never reusing one
+///
+/// Ids are 32 bits, the size of an entity field on the wire. That lasts for a billion spawns,
+/// and the server stops rather than hand out an id twice.
+#[derive(Default)]
+pub struct NetworkIdAllocator {
+    next_id: u32,
+}
+
+impl NetworkIdAllocator {
+    pub fn allocate(&mut self) -> NetworkEntity {
+        let id = NetworkEntity::from_raw(self.next_id);
+        self.next_id = self.next_id.checked_add(1).expect("ran out of network ids");
+        id
+    }
+}
+
+fn assign_network_ids(
+    mut commands: Commands,
+    mut allocator: ResMut<NetworkIdAllocator>,
+    mut network_entities: ResMut<NetworkEntityMap>,
+    new_entities: Query<Entity, (With<Replicated>, Without<NetworkEntity>)>,
+) {
+    for entity in new_entities.iter() {
+        let network_entity = allocator.allocate();
+        network_entities.insert(network_entity, entity);
+        commands.entity(entity).insert(network_entity);
+    }
+}
+
+fn forget_network_ids(
+    removed: RemovedComponents<NetworkEntity>,
+    mut network_entities: ResMut<NetworkEntityMap>,
+    mut rooms: ResMut<Rooms>,
+    mut broadcasts: EventWriter<Broadcast>,
+) {
+    for entity in removed.iter() {
+        let room = rooms.forget_entity(entity);
+        if let Some(network_entity) = network_entities.remove_local(&entity) {
+            // only the clients that could see it have it
+            broadcasts.send(Broadcast::EntityDespawned {
+                entity: network_entity,
+                recipients: rooms.scope(room.as_ref(), Recipients::Everyone),
+            });
+        }
+    }
+}
+
+pub struct NetworkIdPlugin;
+
+impl Plugin for NetworkIdPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkIdAllocator>()
+            .init_resource::<NetworkEntityMap>()
+            .add_stage_after(
+                CoreStage::Update,
+                AssignNetworkIds,
+                SystemStage::single(assign_network_ids),
+            )
+            .add_stage_after(
+                CoreStage::Last,
+                ForgetNetworkIds,
+                SystemStage::single(forget_network_ids),
+            );
+    }
+}
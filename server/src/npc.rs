@@ -0,0 +1,209 @@
+//! NPC behaviour
+//!
+//! Every room gets its own NPCs when it is created. Every NPC runs a small state machine: it
+//! idles for a while, then wanders to a random point near its home, and follows the nearest
+//! player avatar in its room that comes close enough until the player gets away. Timers
+//! advance by one tick per frame, so behaviour is tied to the server tick rather than to wall
+//! clock time.
+
+use crate::avatar::Avatar;
+use crate::network_id::Replicated;
+use crate::rooms::RoomCreated;
+use crate::visibility::{NetworkVisibility, VisibleNearby};
+use crate::{random_position, CREATURE_RADIUS};
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use messages::{RoomId, TICK_RATE};
+use shared_components::movement::MoveTarget;
+use shared_components::spatial::SpatialGrid;
+use shared_components::{NAnimation, NCollider, NTransform, Tilemap, WorldSettings};
+use std::ops::Range;
+
+pub struct NpcSettings {
+    /// NPCs in each room
+    pub count: usize,
+    /// How long an NPC stands still between walks, in seconds
+    pub idle_time: Range<f32>,
+    /// Longest a wander may take before the NPC gives up on it, in seconds
+    pub max_wander_time: f32,
+    /// How far from home an NPC wanders
+    pub wander_radius: f32,
+    /// Players closer than this get followed
+    pub follow_radius: f32,
+    /// Followed players further away than this are let go
+    pub lose_radius: f32,
+    /// How often a follower catches up with where its player went, in seconds
+    pub follow_interval: f32,
+}
+
+impl Default for NpcSettings {
+    fn default() -> Self {
+        NpcSettings {
+            count: 50,
+            idle_time: 1.0..4.0,
+            max_wander_time: 10.,
+            wander_radius: 150.,
+            follow_radius: 80.,
+            lose_radius: 200.,
+            follow_interval: 0.5,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Npc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NpcState {
+    Idle,
+    Wander,
+    Follow(Entity),
+}
+
+#[derive(Component)]
+pub struct NpcBrain {
+    pub state: NpcState,
+    /// Where the NPC wanders around
+    pub home: Vec2,
+    /// Ends the current state, or the current follow step
+    timer: Timer,
+}
+
+impl NpcBrain {
+    fn new(home: Vec2) -> Self {
+        NpcBrain {
+            state: NpcState::Idle,
+            home,
+            timer: Timer::from_seconds(0., false),
+        }
+    }
+
+    fn enter(&mut self, state: NpcState, seconds: f32) {
+        self.state = state;
+        self.timer = Timer::from_seconds(seconds, false);
+    }
+}
+
+/// Fills new rooms with NPCs, once the map is there to keep them out of walls
+fn spawn_npcs(
+    mut commands: Commands,
+    mut created: EventReader<RoomCreated>,
+    mut waiting: Local<Vec<RoomId>>,
+    settings: Res<NpcSettings>,
+    world: Res<WorldSettings>,
+    map: Option<Res<Tilemap>>,
+) {
+    waiting.extend(created.iter().map(|RoomCreated(room)| *room));
+    let map = match map {
+        Some(map) => map,
+        None => return,
+    };
+
+    for room in waiting.drain(..) {
+        for _ in 0..settings.count {
+            let home = random_position(&world, Some(&map), CREATURE_RADIUS);
+            let transform = Transform::from_translation(home.extend(0.));
+
+            commands
+                .spawn_bundle(TransformBundle::from_transform(transform))
+                .insert(NTransform::from(transform))
+                .insert(Npc)
+                .insert(NpcBrain::new(home))
+                .insert(NAnimation::default())
+                .insert(VisibleNearby)
+                .insert(NetworkVisibility::default())
+                .insert(NCollider {
+                    radius: CREATURE_RADIUS,
+                })
+                .insert(room)
+                .insert(Replicated);
+        }
+    }
+}
+
+fn random_in(range: &Range<f32>) -> f32 {
+    range.start + rand::random::<f32>() * (range.end - range.start)
+}
+
+/// A random point at most `radius` from `center`, inside the world
+fn wander_target(center: Vec2, radius: f32, world: &WorldSettings) -> Vec2 {
+    let angle = rand::random::<f32>() * std::f32::consts::TAU;
+    let distance = rand::random::<f32>().sqrt() * radius;
+    let bounds = world.size / 2.;
+    (center + Vec2::from_angle(angle) * distance).clamp(-bounds, bounds)
+}
+
+type Npcs<'a> = (
+    Entity,
+    &'a Transform,
+    &'a mut NpcBrain,
+    &'a RoomId,
+    Option<&'a MoveTarget>,
+);
+
+fn think(
+    mut commands: Commands,
+    settings: Res<NpcSettings>,
+    world: Res<WorldSettings>,
+    grid: Res<SpatialGrid>,
+    mut npcs: Query<Npcs, With<Npc>>,
+    avatars: Query<(&Transform, &RoomId), With<Avatar>>,
+) {
+    let tick = Duration::from_secs_f64(1. / TICK_RATE);
+
+    for (npc, transform, mut brain, room, move_target) in npcs.iter_mut() {
+        let position = transform.translation.truncate();
+        let timer_done = brain.timer.tick(tick).finished();
+        let nearest_player = grid
+            .nearest(position, settings.follow_radius, |entity| {
+                avatars
+                    .get(entity)
+                    .map_or(false, |(_, avatar_room)| avatar_room == room)
+            })
+            .map(|(avatar, _)| avatar);
+
+        match (brain.state, nearest_player) {
+            (NpcState::Idle | NpcState::Wander, Some(player)) => {
+                brain.enter(NpcState::Follow(player), 0.);
+            }
+            (NpcState::Idle, None) if timer_done => {
+                let target = wander_target(brain.home, settings.wander_radius, &world);
+                commands.entity(npc).insert(MoveTarget(target));
+                brain.enter(NpcState::Wander, settings.max_wander_time);
+            }
+            (NpcState::Wander, None) if move_target.is_none() || timer_done => {
+                commands.entity(npc).remove::<MoveTarget>();
+                brain.enter(NpcState::Idle, random_in(&settings.idle_time));
+            }
+            (NpcState::Follow(player), _) if timer_done => {
+                let player_position = avatars
+                    .get(player)
+                    .ok()
+                    .map(|(t, _)| t.translation.truncate())
+                    .filter(|p| p.distance(position) <= settings.lose_radius);
+
+                match player_position {
+                    Some(player_position) => {
+                        commands.entity(npc).insert(MoveTarget(player_position));
+                        brain.enter(NpcState::Follow(player), settings.follow_interval);
+                    }
+                    None => {
+                        commands.entity(npc).remove::<MoveTarget>();
+                        brain.enter(NpcState::Idle, random_in(&settings.idle_time));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct NpcPlugin;
+
+impl Plugin for NpcPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NpcSettings>()
+            .add_system(spawn_npcs)
+            .add_system(think);
+    }
+}
@@ -0,0 +1,92 @@
+//! Static obstacles scattered over the world
+//!
+//! Obstacles are replicated like any other entity, so clients can draw them and keep their
+//! prediction out of them. They don't overlap each other or solid tiles, and sit on whole units
+//! so their quantized positions are exact on clients. They are placed once the map is loaded,
+//! and placed again whenever it changes.
+
+use crate::network_id::Replicated;
+use crate::random_position;
+use bevy::prelude::*;
+use shared_components::{NCollider, NObstacle, NTransform, Tilemap, WorldSettings};
+use std::ops::Range;
+
+pub struct ObstacleSettings {
+    pub count: usize,
+    pub radius: Range<f32>,
+    /// Free space kept between obstacles, so things can walk between them
+    pub spacing: f32,
+}
+
+impl Default for ObstacleSettings {
+    fn default() -> Self {
+        ObstacleSettings {
+            count: 12,
+            radius: 16.0..40.0,
+            spacing: 24.,
+        }
+    }
+}
+
+/// Gives up on placing obstacles after this many tries per obstacle
+const PLACEMENT_TRIES: usize = 20;
+
+fn spawn_obstacles(
+    mut commands: Commands,
+    settings: Res<ObstacleSettings>,
+    world: Res<WorldSettings>,
+    map: Option<Res<Tilemap>>,
+    obstacles: Query<Entity, With<NObstacle>>,
+) {
+    let map = match map {
+        Some(map) if map.is_changed() => map,
+        _ => return,
+    };
+    for obstacle in obstacles.iter() {
+        commands.entity(obstacle).despawn_recursive();
+    }
+
+    let mut placed: Vec<(Vec2, f32)> = Vec::with_capacity(settings.count);
+
+    for _ in 0..settings.count * PLACEMENT_TRIES {
+        if placed.len() == settings.count {
+            break;
+        }
+        let radius = (settings.radius.start
+            + rand::random::<f32>() * (settings.radius.end - settings.radius.start))
+            .round();
+        let position = random_position(&world, Some(&map), radius).round();
+        let overlaps = placed.iter().any(|(other, other_radius)| {
+            other.distance(position) < radius + other_radius + settings.spacing
+        });
+        if overlaps || !map.is_clear(position, radius) {
+            continue;
+        }
+        placed.push((position, radius));
+
+        let transform = Transform::from_translation(position.extend(0.));
+        commands
+            .spawn_bundle(TransformBundle::from_transform(transform))
+            .insert(NTransform::from(transform))
+            .insert(NCollider { radius })
+            .insert(NObstacle {})
+            .insert(Replicated);
+    }
+
+    if placed.len() < settings.count {
+        warn!(
+            "only found room for {} of {} obstacles",
+            placed.len(),
+            settings.count
+        );
+    }
+}
+
+pub struct ObstaclePlugin;
+
+impl Plugin for ObstaclePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ObstacleSettings>()
+            .add_system(spawn_obstacles);
+    }
+}
@@ -0,0 +1,68 @@
+//! Replication of global game state held in resources
+//!
+//! Resources declared with `networked_resources!` are broadcast to everyone whenever they
+//! change, and to joining players as part of their snapshot. Each resource type has to be
+//! registered with [`NetworkResourceAppExt`].
+
+use crate::events::Recipients;
+use crate::{Broadcast, BroadcastMessages, PlayerEvent};
+use bevy::prelude::*;
+use messages::NetworkEntityMap;
+use shared_components::entity_mapping::{map_entities, wire_entity};
+use shared_components::NetworkResource;
+
+fn replicate_resource<R: NetworkResource>(
+    resource: Option<Res<R>>,
+    network_entities: Res<NetworkEntityMap>,
+    mut player_events: EventReader<PlayerEvent>,
+    mut broadcasts: EventWriter<Broadcast>,
+) {
+    let joined: Vec<u64> = player_events
+        .iter()
+        .filter_map(|event| match event {
+            PlayerEvent::PlayerJoined { connection_id } => Some(*connection_id),
+            _ => None,
+        })
+        .collect();
+    let resource = match resource {
+        Some(resource) => resource,
+        None => return,
+    };
+
+    // a change goes to everyone, joining players included
+    let recipients = if resource.is_changed() {
+        Recipients::Everyone
+    } else if !joined.is_empty() {
+        Recipients::Group(joined)
+    } else {
+        return;
+    };
+
+    let mut resource = resource.clone();
+    if let Err(e) = map_entities(&mut resource, &mut |e| {
+        network_entities.get_network(&e).map(wire_entity)
+    }) {
+        warn!("not sending resource {}: {}", R::KIND_ID, e);
+        return;
+    }
+
+    broadcasts.send(Broadcast::ResourceChanged {
+        recipients,
+        kind: R::KIND_ID,
+        data: postcard::to_allocvec(&resource).unwrap(),
+    });
+}
+
+pub trait NetworkResourceAppExt {
+    /// Registers a resource that is replicated to clients
+    fn add_networked_resource<R: NetworkResource>(&mut self) -> &mut Self;
+}
+
+impl NetworkResourceAppExt for App {
+    fn add_networked_resource<R: NetworkResource>(&mut self) -> &mut Self {
+        self.add_system_to_stage(
+            CoreStage::PostUpdate,
+            replicate_resource::<R>.before(BroadcastMessages),
+        )
+    }
+}
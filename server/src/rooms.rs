@@ -0,0 +1,401 @@
+//! Rooms, separate sets of entities inside one server
+//!
+//! Every player is in one room at a time and joins the server in the [`RoomId::LOBBY`].
+//! Entities that belong to a room carry its [`RoomId`]: they are only replicated to the
+//! members of that room, and only interact with entities of the same room. Entities without a
+//! room, like obstacles, are shared by all of them, and so are the map and other resources.
+//!
+//! Players create, list, join and leave rooms with [`PlayerMessage`](messages::PlayerMessage)s
+//! or the matching chat commands, which become [`RoomRequest`]s. Moving to another room
+//! despawns the entities of the old room on the player's client before the player is told the
+//! move is done, and [`RoomChanged`] tells the rest of the server, so the player gets a
+//! snapshot of the new room and an avatar in it. Entities despawned in a room are only
+//! despawned on the clients of its members, like they were only spawned there.
+//! Rooms other than the lobby are removed, with everything in them, once they have been empty
+//! for a while.
+
+use crate::events::Recipients;
+use crate::{Broadcast, PlayerEvent};
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use messages::{NetworkEntity, RoomId, RoomInfo, ServerMessage};
+
+pub struct RoomSettings {
+    /// Empty rooms are removed after this many seconds
+    pub empty_timeout: f64,
+    /// Most rooms there can be at once, the lobby included
+    pub max_rooms: usize,
+    pub max_name_length: usize,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        RoomSettings {
+            empty_timeout: 60.,
+            max_rooms: 32,
+            max_name_length: 32,
+        }
+    }
+}
+
+struct Room {
+    name: String,
+    /// Connections in the room
+    members: HashSet<u64>,
+    /// When the last member left, in seconds since startup
+    empty_since: Option<f64>,
+}
+
+impl Room {
+    fn new(name: String, now: f64) -> Self {
+        Room {
+            name,
+            members: HashSet::new(),
+            empty_since: Some(now),
+        }
+    }
+}
+
+pub struct Rooms {
+    rooms: HashMap<RoomId, Room>,
+    /// The room each connection is in
+    member_of: HashMap<u64, RoomId>,
+    /// The room each replicated entity is in, until its despawn is sent
+    entities: HashMap<Entity, RoomId>,
+    next_id: u32,
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Rooms {
+            rooms: HashMap::from([(RoomId::LOBBY, Room::new("lobby".into(), 0.))]),
+            member_of: HashMap::new(),
+            entities: HashMap::new(),
+            next_id: RoomId::LOBBY.0 + 1,
+        }
+    }
+}
+
+impl Rooms {
+    pub fn room_of(&self, connection_id: u64) -> Option<RoomId> {
+        self.member_of.get(&connection_id).copied()
+    }
+
+    /// Forgets which room a despawned entity was in, returning it
+    pub fn forget_entity(&mut self, entity: Entity) -> Option<RoomId> {
+        self.entities.remove(&entity)
+    }
+
+    /// Narrows recipients down to the members of `room`, entities without one go to everyone
+    pub fn scope(&self, room: Option<&RoomId>, recipients: Recipients) -> Recipients {
+        let members = match room {
+            Some(room) => match self.rooms.get(room) {
+                Some(room) => &room.members,
+                None => return Recipients::Group(Vec::new()),
+            },
+            None => return recipients,
+        };
+        match recipients {
+            Recipients::Everyone => Recipients::Group(members.iter().copied().collect()),
+            Recipients::Group(ids) => {
+                Recipients::Group(ids.into_iter().filter(|id| members.contains(id)).collect())
+            }
+            Recipients::One(id) if members.contains(&id) => Recipients::One(id),
+            Recipients::One(_) => Recipients::Group(Vec::new()),
+        }
+    }
+
+    /// Every room, the lobby first
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .iter()
+            .map(|(id, room)| RoomInfo {
+                id: *id,
+                name: room.name.clone(),
+                players: room.members.len() as u32,
+            })
+            .collect();
+        rooms.sort_by_key(|room| room.id.0);
+        rooms
+    }
+
+    fn create(&mut self, name: String, now: f64) -> RoomId {
+        let id = RoomId(self.next_id);
+        self.next_id += 1;
+        self.rooms.insert(id, Room::new(name, now));
+        id
+    }
+
+    /// Takes a connection out of its room, if it is in one
+    fn remove_member(&mut self, connection_id: u64, now: f64) -> Option<RoomId> {
+        let room = self.member_of.remove(&connection_id)?;
+        if let Some(room) = self.rooms.get_mut(&room) {
+            room.members.remove(&connection_id);
+            if room.members.is_empty() {
+                room.empty_since = Some(now);
+            }
+        }
+        Some(room)
+    }
+
+    /// Moves a connection into a room that exists, returning the room it was in
+    fn move_member(&mut self, connection_id: u64, to: RoomId, now: f64) -> Option<RoomId> {
+        let from = self.remove_member(connection_id, now);
+        let room = self.rooms.get_mut(&to).unwrap();
+        room.members.insert(connection_id);
+        room.empty_since = None;
+        self.member_of.insert(connection_id, to);
+        from
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RoomAction {
+    /// Creates a room and moves into it
+    Create {
+        name: String,
+    },
+    List,
+    Join {
+        room: RoomId,
+    },
+    /// Goes back to the lobby
+    Leave,
+}
+
+/// A player asking to do something with rooms
+#[derive(Debug, Clone)]
+pub struct RoomRequest {
+    pub connection_id: u64,
+    pub action: RoomAction,
+}
+
+/// A player moved to another room, `from` is `None` for players that just joined
+#[derive(Debug, Clone)]
+pub struct RoomChanged {
+    pub connection_id: u64,
+    pub from: Option<RoomId>,
+    pub to: RoomId,
+}
+
+/// A room was created and needs filling, sent for the lobby at startup too
+#[derive(Debug, Clone)]
+pub struct RoomCreated(pub RoomId);
+
+fn create_lobby(mut created: EventWriter<RoomCreated>) {
+    created.send(RoomCreated(RoomId::LOBBY));
+}
+
+fn reply(connection_id: u64, message: ServerMessage) -> Broadcast {
+    Broadcast::Reply {
+        connection_id,
+        message,
+    }
+}
+
+fn refuse(connection_id: u64, reason: impl Into<String>) -> Broadcast {
+    reply(
+        connection_id,
+        ServerMessage::RoomRefused {
+            reason: reason.into(),
+        },
+    )
+}
+
+/// Moves a connection into a room and tells the rest of the server
+fn enter(
+    rooms: &mut Rooms,
+    connection_id: u64,
+    to: RoomId,
+    now: f64,
+    changes: &mut EventWriter<RoomChanged>,
+) {
+    let from = rooms.move_member(connection_id, to, now);
+    debug!(
+        "connection {} moved from room {:?} to {}",
+        connection_id, from, to
+    );
+    changes.send(RoomChanged {
+        connection_id,
+        from,
+        to,
+    });
+}
+
+fn track_players(
+    mut player_events: EventReader<PlayerEvent>,
+    mut rooms: ResMut<Rooms>,
+    time: Res<Time>,
+    mut changes: EventWriter<RoomChanged>,
+) {
+    let now = time.seconds_since_startup();
+    for event in player_events.iter() {
+        match event {
+            PlayerEvent::PlayerJoined { connection_id } => {
+                enter(&mut rooms, *connection_id, RoomId::LOBBY, now, &mut changes)
+            }
+            PlayerEvent::PlayerLeft { connection_id, .. } => {
+                rooms.remove_member(*connection_id, now);
+            }
+        }
+    }
+}
+
+fn handle_room_requests(
+    mut requests: EventReader<RoomRequest>,
+    mut rooms: ResMut<Rooms>,
+    settings: Res<RoomSettings>,
+    time: Res<Time>,
+    mut created: EventWriter<RoomCreated>,
+    mut changes: EventWriter<RoomChanged>,
+    mut broadcasts: EventWriter<Broadcast>,
+) {
+    let now = time.seconds_since_startup();
+    for RoomRequest {
+        connection_id,
+        action,
+    } in requests.iter()
+    {
+        let connection_id = *connection_id;
+        // requests can arrive before the hello, or after the connection is gone
+        let current = match rooms.room_of(connection_id) {
+            Some(current) => current,
+            None => continue,
+        };
+
+        let to = match action {
+            RoomAction::List => {
+                let rooms = rooms.list();
+                broadcasts.send(reply(connection_id, ServerMessage::RoomList { rooms }));
+                continue;
+            }
+            RoomAction::Create { name } => {
+                let name = name.trim();
+                if name.is_empty()
+                    || name.chars().count() > settings.max_name_length
+                    || name.chars().any(char::is_control)
+                {
+                    broadcasts.send(refuse(
+                        connection_id,
+                        format!(
+                            "room names are 1 to {} characters",
+                            settings.max_name_length
+                        ),
+                    ));
+                    continue;
+                }
+                if rooms.rooms.len() >= settings.max_rooms {
+                    broadcasts.send(refuse(connection_id, "there are too many rooms"));
+                    continue;
+                }
+                let room = rooms.create(name.into(), now);
+                debug!(
+                    "connection {} created room {} {:?}",
+                    connection_id, room, name
+                );
+                created.send(RoomCreated(room));
+                room
+            }
+            RoomAction::Join { room } if !rooms.rooms.contains_key(room) => {
+                broadcasts.send(refuse(connection_id, format!("there is no room {}", room)));
+                continue;
+            }
+            RoomAction::Join { room } => *room,
+            RoomAction::Leave => RoomId::LOBBY,
+        };
+
+        if to == current {
+            broadcasts.send(refuse(connection_id, format!("already in room {}", to)));
+            continue;
+        }
+        enter(&mut rooms, connection_id, to, now, &mut changes);
+    }
+}
+
+/// Despawns the entities of the room a player left on that player's client only, then tells
+/// them which room they are in
+fn switch_rooms_on_clients(
+    mut changes: EventReader<RoomChanged>,
+    rooms: Res<Rooms>,
+    entities: Query<(&NetworkEntity, &RoomId)>,
+    mut broadcasts: EventWriter<Broadcast>,
+) {
+    for change in changes.iter() {
+        if let Some(from) = change.from {
+            for (entity, _) in entities.iter().filter(|(_, room)| **room == from) {
+                broadcasts.send(Broadcast::EntityDespawned {
+                    entity: *entity,
+                    recipients: Recipients::One(change.connection_id),
+                });
+            }
+        }
+        // the room can't be gone yet, empty rooms are only removed before the update stage
+        broadcasts.send(reply(
+            change.connection_id,
+            ServerMessage::RoomJoined {
+                room: change.to,
+                name: rooms.rooms[&change.to].name.clone(),
+            },
+        ));
+    }
+}
+
+type MovedEntities = (Changed<RoomId>, With<NetworkEntity>);
+
+/// Remembers the room of replicated entities, so their despawn can go to its members
+fn track_entity_rooms(mut rooms: ResMut<Rooms>, entities: Query<(Entity, &RoomId), MovedEntities>) {
+    for (entity, room) in entities.iter() {
+        rooms.entities.insert(entity, *room);
+    }
+}
+
+/// Removes rooms that have been empty for too long, along with their entities
+fn remove_empty_rooms(
+    mut commands: Commands,
+    mut rooms: ResMut<Rooms>,
+    settings: Res<RoomSettings>,
+    time: Res<Time>,
+    entities: Query<(Entity, &RoomId)>,
+) {
+    let now = time.seconds_since_startup();
+    let expired: Vec<RoomId> = rooms
+        .rooms
+        .iter()
+        .filter(|(id, room)| {
+            **id != RoomId::LOBBY
+                && room
+                    .empty_since
+                    .map_or(false, |since| now - since >= settings.empty_timeout)
+        })
+        .map(|(id, _)| *id)
+        .collect();
+
+    for id in expired {
+        let room = rooms.rooms.remove(&id).unwrap();
+        debug!("removing empty room {} {:?}", id, room.name);
+        for (entity, _) in entities.iter().filter(|(_, room)| **room == id) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+pub struct RoomPlugin;
+
+impl Plugin for RoomPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RoomSettings>()
+            .init_resource::<Rooms>()
+            .add_event::<RoomRequest>()
+            .add_event::<RoomChanged>()
+            .add_event::<RoomCreated>()
+            .add_startup_system(create_lobby)
+            .add_system(track_players)
+            .add_system(handle_room_requests.after(track_players))
+            .add_system(switch_rooms_on_clients.after(handle_room_requests))
+            // network ids are assigned after the update stage
+            .add_system_to_stage(CoreStage::PostUpdate, track_entity_rooms)
+            // before the update stage, so no system queues commands for what it despawns
+            .add_system_to_stage(CoreStage::PreUpdate, remove_empty_rooms);
+    }
+}
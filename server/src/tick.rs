@@ -0,0 +1,25 @@
+//! The server tick counter
+//!
+//! [`ServerTick`] is advanced at the start of every frame, and the schedule runs at
+//! [`messages::TICK_RATE`] frames per second, so ticks double as server time. Every batch
+//! of messages sent to a connection starts with the tick it was sent at.
+
+use bevy::prelude::*;
+use messages::Tick;
+
+/// Number of the current server frame
+#[derive(Default, Debug, Clone, Copy, Deref)]
+pub struct ServerTick(pub Tick);
+
+fn advance_tick(mut tick: ResMut<ServerTick>) {
+    tick.0 = tick.0.wrapping_add(1);
+}
+
+pub struct TickPlugin;
+
+impl Plugin for TickPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerTick>()
+            .add_system_to_stage(CoreStage::First, advance_tick);
+    }
+}
@@ -0,0 +1,54 @@
+//! The map of the world
+//!
+//! The map is loaded at startup and, once loaded, becomes the replicated [`Tilemap`] resource
+//! and sets the size of the world. Reloading the asset replaces both, and clients get the new
+//! map like any other resource change.
+
+use bevy::prelude::*;
+use shared_components::tilemap::TilemapAssetPlugin;
+use shared_components::{Tilemap, WorldSettings};
+
+const MAP_PATH: &str = "world.tilemap.json";
+
+struct MapHandle(Handle<Tilemap>);
+
+fn load_map(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(MapHandle(asset_server.load(MAP_PATH)));
+}
+
+fn apply_loaded_map(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<Tilemap>>,
+    handle: Res<MapHandle>,
+    maps: Res<Assets<Tilemap>>,
+    mut world: ResMut<WorldSettings>,
+) {
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle: loaded } | AssetEvent::Modified { handle: loaded }
+                if *loaded == handle.0 => {}
+            _ => continue,
+        }
+        let map = match maps.get(&handle.0) {
+            Some(map) => map,
+            None => continue,
+        };
+
+        debug!(
+            "loaded {}: {}x{} tiles of {}",
+            MAP_PATH, map.width, map.height, map.tile_size
+        );
+        world.size = map.size();
+        commands.insert_resource(map.clone());
+    }
+}
+
+pub struct TilemapPlugin;
+
+impl Plugin for TilemapPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(TilemapAssetPlugin)
+            .add_startup_system(load_map)
+            .add_system(apply_loaded_map);
+    }
+}
@@ -0,0 +1,201 @@
+//! Per-connection visibility of replicated components
+//!
+//! Every networked kind declares a [`VisibilityRule`]. Components visible to everyone go to
+//! every connection, owner-only components go to the connection of the player whose
+//! [`PlayerId`] is on the entity, and filtered components go to the connections listed in the
+//! entity's [`NetworkVisibility`]. Gameplay systems act as the predicates for filtered
+//! components by keeping that set up to date: connections that enter it get the current state,
+//! connections that leave it are told to remove the component.
+//!
+//! The predicate here is for [`VisibleNearby`] entities, whose filtered components only go to
+//! players with their avatar close by, so far away animations don't cost any bandwidth.
+
+use crate::avatar::Avatar;
+use crate::events::Recipients;
+use crate::{BroadcastMessages, Collisions, PlayerEvent};
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use messages::{PlayerId, RoomId, VisibilityRule};
+
+pub struct VisibilitySettings {
+    /// Players see the filtered components of [`VisibleNearby`] entities this close to their
+    /// avatar
+    pub range: f32,
+}
+
+impl Default for VisibilitySettings {
+    fn default() -> Self {
+        VisibilitySettings { range: 400. }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct CommitVisibility;
+
+/// Which player said hello on which connection
+#[derive(Default)]
+pub struct ConnectionMappings {
+    players: HashMap<u64, PlayerId>,
+    connections: HashMap<PlayerId, u64>,
+}
+
+impl ConnectionMappings {
+    /// Maps a player to a connection, replacing any older connection of the same player
+    pub fn insert(&mut self, connection_id: u64, player_id: PlayerId) {
+        if let Some(old_connection) = self.connections.insert(player_id, connection_id) {
+            self.players.remove(&old_connection);
+        }
+        if let Some(old_player) = self.players.insert(connection_id, player_id) {
+            if old_player != player_id {
+                self.connections.remove(&old_player);
+            }
+        }
+    }
+
+    /// Forgets every connection the predicate returns false for
+    pub fn retain(&mut self, mut keep: impl FnMut(u64, PlayerId) -> bool) {
+        let connections = &mut self.connections;
+        self.players.retain(|connection_id, player_id| {
+            let retained = keep(*connection_id, *player_id);
+            if !retained {
+                connections.remove(player_id);
+            }
+            retained
+        });
+    }
+
+    pub fn player(&self, connection_id: u64) -> Option<PlayerId> {
+        self.players.get(&connection_id).copied()
+    }
+
+    pub fn connection(&self, player_id: &PlayerId) -> Option<u64> {
+        self.connections.get(player_id).copied()
+    }
+}
+
+/// Connections that can see the filtered components of an entity
+#[derive(Component, Default, Debug)]
+pub struct NetworkVisibility {
+    pub connections: HashSet<u64>,
+    /// Connections that were sent the filtered components so far
+    sent: HashSet<u64>,
+}
+
+impl NetworkVisibility {
+    /// Connections that have to be sent the current state
+    pub fn entered(&self) -> impl Iterator<Item = u64> + '_ {
+        self.connections.difference(&self.sent).copied()
+    }
+
+    /// Connections that have to be told to remove the filtered components
+    pub fn left(&self) -> impl Iterator<Item = u64> + '_ {
+        self.sent.difference(&self.connections).copied()
+    }
+}
+
+/// Shows the filtered components of an entity to the players near it, in its room
+#[derive(Component)]
+pub struct VisibleNearby;
+
+/// The predicate of [`VisibleNearby`] entities, run once everything has moved this tick
+fn update_nearby_visibility(
+    settings: Res<VisibilitySettings>,
+    connections: Res<ConnectionMappings>,
+    avatars: Query<(&PlayerId, &Transform, Option<&RoomId>), With<Avatar>>,
+    mut entities: Query<(&Transform, Option<&RoomId>, &mut NetworkVisibility), With<VisibleNearby>>,
+) {
+    let viewers: Vec<(u64, Vec2, Option<&RoomId>)> = avatars
+        .iter()
+        .filter_map(|(owner, transform, room)| {
+            let connection_id = connections.connection(owner)?;
+            Some((connection_id, transform.translation.truncate(), room))
+        })
+        .collect();
+
+    for (transform, room, mut visibility) in entities.iter_mut() {
+        let position = transform.translation.truncate();
+        let visible: HashSet<u64> = viewers
+            .iter()
+            .filter(|(_, viewer, viewer_room)| {
+                *viewer_room == room && viewer.distance(position) <= settings.range
+            })
+            .map(|(connection_id, _, _)| *connection_id)
+            .collect();
+        if visibility.connections != visible {
+            visibility.connections = visible;
+        }
+    }
+}
+
+/// Forgets connections that are gone, so nobody tries to update them
+fn forget_left_connections(
+    mut player_events: EventReader<PlayerEvent>,
+    mut entities: Query<&mut NetworkVisibility>,
+) {
+    let left: Vec<u64> = player_events
+        .iter()
+        .filter_map(|event| match event {
+            PlayerEvent::PlayerLeft { connection_id, .. } => Some(*connection_id),
+            _ => None,
+        })
+        .collect();
+    if left.is_empty() {
+        return;
+    }
+
+    for mut visibility in entities.iter_mut() {
+        for connection_id in &left {
+            visibility.connections.remove(connection_id);
+            visibility.sent.remove(connection_id);
+        }
+    }
+}
+
+/// Connections a component with the given rule goes to
+pub fn recipients(
+    rule: VisibilityRule,
+    owner: Option<&PlayerId>,
+    visibility: Option<&NetworkVisibility>,
+    connections: &ConnectionMappings,
+) -> Recipients {
+    match rule {
+        VisibilityRule::Everyone => Recipients::Everyone,
+        VisibilityRule::OwnerOnly => Recipients::Group(
+            owner
+                .and_then(|owner| connections.connection(owner))
+                .into_iter()
+                .collect(),
+        ),
+        VisibilityRule::Filtered => Recipients::Group(
+            visibility
+                .map(|visibility| visibility.connections.iter().copied().collect())
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+/// Remembers who got the filtered components, once every networked kind has been broadcast
+fn commit_visibility(mut entities: Query<&mut NetworkVisibility>) {
+    for mut visibility in entities.iter_mut() {
+        if visibility.sent != visibility.connections {
+            visibility.sent = visibility.connections.clone();
+        }
+    }
+}
+
+pub struct VisibilityPlugin;
+
+impl Plugin for VisibilityPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ConnectionMappings>()
+            .init_resource::<VisibilitySettings>()
+            .add_system(forget_left_connections.before(update_nearby_visibility))
+            .add_system(update_nearby_visibility.after(Collisions))
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                commit_visibility
+                    .label(CommitVisibility)
+                    .before(BroadcastMessages),
+            );
+    }
+}
@@ -0,0 +1,89 @@
+//! Timings of the spatial grid with 10k entities, run with `cargo bench -p shared_components`
+
+use bevy::prelude::*;
+use shared_components::spatial::SpatialGrid;
+use std::time::Instant;
+
+const ENTITIES: u32 = 10_000;
+const QUERIES: u32 = 10_000;
+/// Same world size as the server's
+const WORLD_SIZE: Vec2 = Vec2::new(1200., 600.);
+
+fn random_position() -> Vec2 {
+    (Vec2::new(rand::random(), rand::random()) - 0.5) * WORLD_SIZE
+}
+
+fn bench(name: &str, iterations: u32, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<32} {:>10.2?} total, {:>10.2?} each",
+        name,
+        elapsed,
+        elapsed / iterations
+    );
+}
+
+fn main() {
+    let entities: Vec<_> = (0..ENTITIES).map(Entity::from_raw).collect();
+    let positions: Vec<_> = entities.iter().map(|_| random_position()).collect();
+    let queries: Vec<_> = (0..QUERIES).map(|_| random_position()).collect();
+
+    let mut grid = SpatialGrid::default();
+    let mut inserted = entities.iter().zip(&positions);
+    bench("insert", ENTITIES, || {
+        let (entity, position) = inserted.next().unwrap();
+        grid.insert(*entity, *position);
+    });
+
+    // what a tick of everything walking looks like
+    let mut moved = entities.iter().zip(&positions).cycle();
+    bench("move by one unit", ENTITIES, || {
+        let (entity, position) = moved.next().unwrap();
+        grid.insert(*entity, *position + Vec2::X);
+    });
+
+    // every result ends up in here and is printed, so no query is optimized away
+    let mut found = 0;
+    let mut points = queries.iter().cycle();
+    bench("within radius 50", QUERIES, || {
+        let point = *points.next().unwrap();
+        found += grid.within_radius(point, 50.).count();
+    });
+    bench("within rect 100x100", QUERIES, || {
+        let point = *points.next().unwrap();
+        found += grid.within_rect(point - 50., point + 50.).count();
+    });
+    bench("nearest within 200", QUERIES, || {
+        let point = *points.next().unwrap();
+        found += grid.nearest(point, 200., |_| true).is_some() as usize;
+    });
+    // only one in a hundred entities qualifies, like players among NPCs
+    bench("nearest filtered within 200", QUERIES, || {
+        let point = *points.next().unwrap();
+        found += grid
+            .nearest(point, 200., |entity| entity.id() % 100 == 0)
+            .is_some() as usize;
+    });
+
+    // the scan the grid replaces
+    bench("linear scan radius 50", QUERIES / 10, || {
+        let point = *points.next().unwrap();
+        let radius_squared = 50. * 50.;
+        found += positions
+            .iter()
+            .filter(|position| position.distance_squared(point) <= radius_squared)
+            .count();
+    });
+
+    println!("{} entities found", found);
+
+    let mut removed = entities.iter();
+    bench("remove", ENTITIES, || {
+        grid.remove(*removed.next().unwrap());
+    });
+    assert!(grid.is_empty());
+}
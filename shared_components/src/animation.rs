@@ -0,0 +1,260 @@
+//! Sprite animation timing, without any rendering
+//!
+//! An [`AnimationSet`] holds the animations of a sprite sheet and how long each frame is shown,
+//! parsed from the JSON Aseprite exports next to the sheet. It doesn't touch textures, so the
+//! server can load the same asset as the client with [`AnimationAssetPlugin`] and know how long
+//! animations take. An [`Animator`] plays an animation from a set; the client turns its
+//! current frame into a sprite.
+//!
+//! Animations are the frame tags of the export. A `loop` tag inside another tag marks the
+//! frames that repeat once the animation has played through the ones before it.
+
+use bevy::asset::{AssetLoader, BoxedFuture, Error, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::{Duration, HashMap};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default)]
+pub struct Animation {
+    start_index: usize,
+    end_index: usize,
+    loop_start: usize,
+    loop_end: usize,
+}
+
+/// The animations of one sprite sheet
+#[derive(Debug, TypeUuid)]
+#[uuid = "5f0a3b1e-8c57-4f43-9a0e-2d6c1b7e94a2"]
+pub struct AnimationSet {
+    animations: HashMap<String, Animation>,
+    frame_times: Vec<Duration>,
+}
+
+impl AnimationSet {
+    pub fn from_aseprite(data: &aseprite::SpritesheetData) -> Result<Self, Error> {
+        let frame_tags = data
+            .meta
+            .frame_tags
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("missing frameTags from spritesheet"))?;
+
+        let (loops, others): (Vec<_>, Vec<_>) =
+            frame_tags.iter().partition(|tag| &tag.name == "loop");
+
+        let mut animations = HashMap::with_capacity(others.len());
+
+        for animation in others {
+            // find loop for this animation
+            let (loop_start, loop_end) = loops
+                .iter()
+                .find(|l| l.from >= animation.from && l.to <= animation.to)
+                .map(|l| (l.from, l.to))
+                .unwrap_or((animation.from, animation.to));
+
+            animations.insert(
+                animation.name.clone(),
+                Animation {
+                    start_index: animation.from as usize,
+                    end_index: animation.to as usize,
+                    loop_start: loop_start as usize,
+                    loop_end: loop_end as usize,
+                },
+            );
+        }
+
+        let frame_times = data
+            .frames
+            .iter()
+            .map(|frame| Duration::from_millis(frame.duration as u64))
+            .collect();
+
+        Ok(AnimationSet {
+            animations,
+            frame_times,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Animation> {
+        self.animations.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.animations.keys().map(|name| name.as_str())
+    }
+
+    /// How long it takes to play an animation through once, from its first to its last frame
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        let animation = self.get(name)?;
+        Some(self.frames_time(animation.start_index, animation.end_index))
+    }
+
+    /// How long one round of the looping part of an animation takes
+    pub fn loop_duration(&self, name: &str) -> Option<Duration> {
+        let animation = self.get(name)?;
+        Some(self.frames_time(animation.loop_start, animation.loop_end))
+    }
+
+    /// How long the frames before the looping part of an animation take
+    pub fn lead_in(&self, name: &str) -> Option<Duration> {
+        let animation = self.get(name)?;
+        Some(
+            self.frame_times[animation.start_index..animation.loop_start]
+                .iter()
+                .sum(),
+        )
+    }
+
+    fn frames_time(&self, first: usize, last: usize) -> Duration {
+        self.frame_times[first..=last].iter().sum()
+    }
+}
+
+/// Plays animations of an [`AnimationSet`]
+#[derive(Debug, Clone, Component)]
+pub struct Animator {
+    set: Arc<AnimationSet>,
+    current_animation: Animation,
+    animation_timer: Timer,
+    current_frame: usize,
+    next_animation: Option<Animation>,
+}
+
+impl Animator {
+    /// Starts out playing the set's idle animation
+    pub fn new(set: Arc<AnimationSet>) -> Self {
+        let idle = set
+            .get("idle")
+            .expect("animation set should have idle animation")
+            .clone();
+        Animator {
+            current_frame: idle.start_index,
+            animation_timer: Timer::new(set.frame_times[idle.start_index], false),
+            current_animation: idle,
+            next_animation: None,
+            set,
+        }
+    }
+
+    /// Switches to an animation once the current one has played through
+    pub fn play(&mut self, name: &str) {
+        if let Some(anim) = self.set.get(name).cloned() {
+            self.next_animation = Some(anim);
+        } else {
+            debug!("attempted to play unknown animation '{}'", name);
+        }
+    }
+
+    /// Switches to an animation right away
+    pub fn set_animation(&mut self, name: &str) {
+        if let Some(anim) = self.set.get(name).cloned() {
+            self.current_animation = anim;
+            self.current_frame = self.current_animation.start_index;
+            self.animation_timer
+                .set_duration(self.set.frame_times[self.current_frame]);
+            self.animation_timer.reset();
+        } else {
+            debug!("attempted to set unknown animation '{}'", name);
+        }
+    }
+
+    /// Frame of the sprite sheet to show
+    pub fn current_frame(&self) -> usize {
+        self.current_frame
+    }
+
+    /// Advances the animation by `delta`, returns whether that moved it to another frame
+    pub fn tick(&mut self, delta: Duration) -> bool {
+        if self.animation_timer.tick(delta).finished() {
+            self.next_frame();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Skips ahead `elapsed` into the current animation, from the frame it is on
+    pub fn seek(&mut self, elapsed: Duration) {
+        let mut remaining = elapsed + self.animation_timer.elapsed();
+        loop {
+            let duration = self.animation_timer.duration();
+            if remaining < duration {
+                break;
+            }
+
+            // whole loops end up on the same frame, skip them
+            let animation = &self.current_animation;
+            if self.next_animation.is_none() && self.current_frame == animation.loop_start {
+                let loop_time = self
+                    .set
+                    .frames_time(animation.loop_start, animation.loop_end);
+                if loop_time.is_zero() {
+                    break;
+                }
+                remaining =
+                    Duration::from_nanos((remaining.as_nanos() % loop_time.as_nanos()) as u64);
+                if remaining < duration {
+                    break;
+                }
+            }
+
+            remaining -= duration;
+            self.next_frame();
+        }
+        self.animation_timer.set_elapsed(remaining);
+    }
+
+    /// Moves on to the next frame and restarts the frame timer
+    fn next_frame(&mut self) {
+        self.current_frame += 1;
+
+        // if we have a next animation waiting, continue until end
+        if self.next_animation.is_some() {
+            if self.current_frame > self.current_animation.end_index {
+                self.current_animation = self.next_animation.take().unwrap();
+                self.current_frame = self.current_animation.start_index;
+            }
+        } else if self.current_frame > self.current_animation.loop_end {
+            self.current_frame = self.current_animation.loop_start;
+        }
+
+        let new_duration = self.set.frame_times[self.current_frame];
+        self.animation_timer.set_duration(new_duration);
+        self.animation_timer.reset();
+    }
+}
+
+/// Loads an [`AnimationSet`] from an Aseprite JSON export, ignoring its image
+#[derive(Default)]
+struct AsepriteAnimationLoader;
+
+impl AssetLoader for AsepriteAnimationLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, anyhow::Result<(), Error>> {
+        Box::pin(async move {
+            let data: aseprite::SpritesheetData = serde_json::from_slice(bytes)?;
+            let set = AnimationSet::from_aseprite(&data)?;
+            load_context.set_default_asset(LoadedAsset::new(set));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["aseprite.json"]
+    }
+}
+
+/// Loads animation sets without rendering, for the server
+///
+/// The client has its own loader for the same files, which builds the texture atlas too.
+pub struct AnimationAssetPlugin;
+
+impl Plugin for AnimationAssetPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<AnimationSet>()
+            .init_asset_loader::<AsepriteAnimationLoader>();
+    }
+}
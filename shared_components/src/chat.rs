@@ -0,0 +1,46 @@
+//! Chat between players
+//!
+//! Clients send what the player typed as [`SendChat`](crate::SendChat). The server relays it as
+//! [`ChatMessage`](crate::ChatMessage)s on one of the [`ChatChannel`]s, or runs it as a command
+//! if it starts with [`COMMAND_PREFIX`]. Messages longer than [`MAX_MESSAGE_LENGTH`] are
+//! rejected by the server, and clients stop taking input at that length.
+
+/// Longest message, in characters, the server relays
+pub const MAX_MESSAGE_LENGTH: usize = 200;
+
+/// Lines starting with this are commands, like `/help`
+pub const COMMAND_PREFIX: char = '/';
+
+/// Who a chat message went to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatChannel {
+    /// Every player
+    Global,
+    /// Players with their avatar near the sender's
+    Nearby,
+    /// A single player
+    Whisper,
+    /// Command replies and notices from the server itself
+    System,
+}
+
+impl ChatChannel {
+    pub fn as_u8(self) -> u8 {
+        match self {
+            ChatChannel::Global => 0,
+            ChatChannel::Nearby => 1,
+            ChatChannel::Whisper => 2,
+            ChatChannel::System => 3,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ChatChannel::Global),
+            1 => Some(ChatChannel::Nearby),
+            2 => Some(ChatChannel::Whisper),
+            3 => Some(ChatChannel::System),
+            _ => None,
+        }
+    }
+}
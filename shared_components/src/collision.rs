@@ -0,0 +1,172 @@
+//! Collision rules shared by the server simulation and client prediction
+//!
+//! Everything that collides is a circle, an [`NCollider`]. Static obstacles are marked with
+//! [`NObstacle`] and never move; other colliders are pushed out of them and out of the solid
+//! tiles of the [`Tilemap`], and kept inside the world bounds of [`WorldSettings`]. The server
+//! also separates overlapping movers from each other with [`resolve_collisions`], which the
+//! client can't predict, so client prediction only uses [`StaticCollision`] and leaves the rest
+//! to reconciliation. Movers in different rooms (see [`RoomId`]) pass through each other, while
+//! obstacles are in every room.
+//!
+//! Obstacles are read from their [`NTransform`], which is the same on both ends as long as
+//! obstacles sit on whole units.
+
+use crate::movement::{MoveTarget, MovementSettings};
+use crate::spatial::SpatialGrid;
+use crate::{NCollider, NObstacle, NTransform, Tilemap, WorldSettings};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use messages::RoomId;
+
+/// Position of a circle at `position` pushed out of a circle at `center`, if they overlap
+pub fn push_out(position: Vec2, radius: f32, center: Vec2, center_radius: f32) -> Vec2 {
+    let offset = position - center;
+    let min_distance = radius + center_radius;
+    if offset.length_squared() >= min_distance * min_distance {
+        return position;
+    }
+    // a circle right on the center is pushed out in a fixed direction, to agree everywhere
+    let direction = offset.try_normalize().unwrap_or(Vec2::Y);
+    center + direction * min_distance
+}
+
+/// Position of a circle at `position` pushed out of the rectangle from `min` to `max`
+pub fn push_out_of_rect(position: Vec2, radius: f32, min: Vec2, max: Vec2) -> Vec2 {
+    let closest = position.clamp(min, max);
+    let offset = position - closest;
+    if offset.length_squared() >= radius * radius {
+        return position;
+    }
+    if let Some(direction) = offset.try_normalize() {
+        return closest + direction * radius;
+    }
+
+    // the center is inside, leave by the nearest side
+    let exits = [
+        (position.x - min.x, Vec2::new(min.x - radius, position.y)),
+        (max.x - position.x, Vec2::new(max.x + radius, position.y)),
+        (position.y - min.y, Vec2::new(position.x, min.y - radius)),
+        (max.y - position.y, Vec2::new(position.x, max.y + radius)),
+    ];
+    exits
+        .into_iter()
+        .min_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, exit)| exit)
+        .unwrap()
+}
+
+/// Keeps a circle inside the world
+pub fn clamp_to_world(position: Vec2, radius: f32, world: &WorldSettings) -> Vec2 {
+    let bounds = (world.size / 2. - radius).max(Vec2::ZERO);
+    position.clamp(-bounds, bounds)
+}
+
+/// The world bounds, solid tiles and obstacles colliders are kept out of
+#[derive(SystemParam)]
+pub struct StaticCollision<'w, 's> {
+    // clients get the world settings and map some time after starting
+    world: Option<Res<'w, WorldSettings>>,
+    map: Option<Res<'w, Tilemap>>,
+    obstacles: Query<'w, 's, (&'static NTransform, &'static NCollider), With<NObstacle>>,
+}
+
+impl<'w, 's> StaticCollision<'w, 's> {
+    /// Closest position to `position` a circle of `radius` may be at
+    pub fn resolve(&self, position: Vec2, radius: f32) -> Vec2 {
+        let position = self
+            .obstacles
+            .iter()
+            .fold(position, |position, (n_transform, collider)| {
+                push_out(position, radius, n_transform.translation, collider.radius)
+            });
+        let position = match &self.map {
+            Some(map) => map
+                .solid_rects(position - radius, position + radius)
+                .fold(position, |position, (min, max)| {
+                    push_out_of_rect(position, radius, min, max)
+                }),
+            None => position,
+        };
+        match &self.world {
+            Some(world) => clamp_to_world(position, radius, world),
+            None => position,
+        }
+    }
+}
+
+/// Moves new targets to where their collider can actually get to
+pub fn clamp_move_targets(
+    collision: StaticCollision,
+    mut targets: Query<(&mut MoveTarget, &NCollider), Changed<MoveTarget>>,
+) {
+    for (mut target, collider) in targets.iter_mut() {
+        let clamped = collision.resolve(target.0, collider.radius);
+        if clamped != target.0 {
+            target.0 = clamped;
+        }
+    }
+}
+
+type Movers<'a> = (Entity, &'a mut Transform, &'a NCollider, Option<&'a RoomId>);
+
+/// Separates overlapping colliders, then keeps them out of obstacles and inside the world
+///
+/// Overlapping pairs are found through the [`SpatialGrid`], which lags a tick behind, so the
+/// search is padded by how far movers can have moved since. Pairs that got pushed further
+/// apart in a tick are separated a tick late.
+pub fn resolve_collisions(
+    collision: StaticCollision,
+    grid: Res<SpatialGrid>,
+    settings: Res<MovementSettings>,
+    mut movers: Query<Movers, Without<NObstacle>>,
+) {
+    let circles: HashMap<_, _> = movers
+        .iter()
+        .map(|(entity, transform, collider, room)| {
+            let circle = (
+                transform.translation.truncate(),
+                collider.radius,
+                room.copied(),
+            );
+            (entity, circle)
+        })
+        .collect();
+    let max_radius = circles
+        .values()
+        .fold(0., |max, (_, radius, _)| radius.max(max));
+    let slack = 2. * settings.speed;
+
+    // each of an overlapping pair moves half the overlap away from the other
+    let mut pushes: HashMap<Entity, Vec2> = HashMap::default();
+    for (a, (a_position, a_radius, a_room)) in circles.iter() {
+        let search_radius = a_radius + max_radius + slack;
+        for (b, _) in grid.within_radius(*a_position, search_radius) {
+            // every pair once
+            if b <= *a {
+                continue;
+            }
+            let (b_position, b_radius) = match circles.get(&b) {
+                Some((position, radius, room)) if room == a_room => (position, radius),
+                _ => continue,
+            };
+            let offset = *b_position - *a_position;
+            let overlap = a_radius + b_radius - offset.length();
+            if overlap <= 0. {
+                continue;
+            }
+            let direction = offset.try_normalize().unwrap_or(Vec2::X);
+            *pushes.entry(*a).or_default() -= direction * overlap / 2.;
+            *pushes.entry(b).or_default() += direction * overlap / 2.;
+        }
+    }
+
+    for (entity, (position, radius, _)) in circles {
+        let push = pushes.get(&entity).copied().unwrap_or_default();
+        let resolved = collision.resolve(position + push, radius);
+        if resolved != position {
+            let mut transform = movers.get_mut(entity).unwrap().1;
+            transform.translation = resolved.extend(transform.translation.z);
+        }
+    }
+}
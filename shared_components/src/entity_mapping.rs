@@ -0,0 +1,89 @@
+//! Remapping of entity references inside networked components
+//!
+//! Components may refer to other replicated entities through `Entity` or `Option<Entity>`
+//! fields (entity fields of components declared with `networked!` have to be `Option<Entity>`,
+//! since components need a `Default`). On the wire such a field carries the network id of the
+//! referenced entity instead of a server `Entity`: the server maps its entities to
+//! [`wire_entity`]s before serializing, and the client maps those to its local entities after
+//! deserializing, both through [`map_entities`].
+//!
+//! A plain `Entity` field that can't be mapped fails the whole value, so neither side ever
+//! passes on an entity that means something else here, like a server entity index or an
+//! entity a client made up.
+
+use bevy::prelude::*;
+use bevy::reflect::ReflectMut;
+use messages::NetworkEntity;
+use std::fmt::{Display, Formatter};
+
+/// Encodes a network id as an `Entity`, so it can travel in an entity field
+///
+/// `Entity` serializes as its 32 bit index, the size of a network id.
+pub fn wire_entity(network_entity: NetworkEntity) -> Entity {
+    Entity::from_raw(network_entity.raw())
+}
+
+/// Decodes a network id from an entity field written with [`wire_entity`]
+pub fn network_entity(wire_entity: Entity) -> NetworkEntity {
+    NetworkEntity::from_raw(wire_entity.id())
+}
+
+/// A plain `Entity` field the mapper couldn't resolve
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnmappedEntity(pub Entity);
+
+impl Display for UnmappedEntity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no mapping for entity {:?}", self.0)
+    }
+}
+
+/// Walks a reflected value and maps every `Entity` and `Option<Entity>` in it
+///
+/// Optional references the mapper can't resolve are cleared. A plain `Entity` field it can't
+/// resolve is an error, and the value should be dropped.
+pub fn map_entities(
+    value: &mut dyn Reflect,
+    mapper: &mut dyn FnMut(Entity) -> Option<Entity>,
+) -> Result<(), UnmappedEntity> {
+    if let Some(entity) = value.downcast_mut::<Entity>() {
+        *entity = mapper(*entity).ok_or(UnmappedEntity(*entity))?;
+        return Ok(());
+    }
+
+    if let Some(maybe_entity) = value.downcast_mut::<Option<Entity>>() {
+        *maybe_entity = maybe_entity.and_then(&mut *mapper);
+        return Ok(());
+    }
+
+    match value.reflect_mut() {
+        ReflectMut::Struct(s) => {
+            for i in 0..s.field_len() {
+                map_entities(s.field_at_mut(i).unwrap(), mapper)?;
+            }
+        }
+        ReflectMut::TupleStruct(s) => {
+            for i in 0..s.field_len() {
+                map_entities(s.field_mut(i).unwrap(), mapper)?;
+            }
+        }
+        ReflectMut::Tuple(t) => {
+            for i in 0..t.field_len() {
+                map_entities(t.field_mut(i).unwrap(), mapper)?;
+            }
+        }
+        ReflectMut::List(l) => {
+            for i in 0..l.len() {
+                map_entities(l.get_mut(i).unwrap(), mapper)?;
+            }
+        }
+        ReflectMut::Array(a) => {
+            for i in 0..a.len() {
+                map_entities(a.get_mut(i).unwrap(), mapper)?;
+            }
+        }
+        // map keys can't be changed in place, so maps are not walked
+        ReflectMut::Map(_) | ReflectMut::Value(_) => {}
+    }
+    Ok(())
+}
@@ -1,23 +1,53 @@
 //! Shared networked components
 //!
 //! All components should derive Reflect, Component, and Serialize + Deserialize
+//!
+//! Float fields can be quantized on the wire by tagging them with
+//! `#[serde(with = "Quantized::<Spec>")]`, see [`quantize`]. References to other replicated
+//! entities are `Option<Entity>` fields, remapped on both ends by [`entity_mapping`].
+//!
+//! Components are delivered reliably unless tagged `#[delivery(Unreliable)]`, in which case
+//! stale updates may be dropped in favour of newer ones. They go to every client unless tagged
+//! `#[visibility(OwnerOnly)]` or `#[visibility(Filtered)]`, after any `#[delivery]` tag.
+//!
+//! Movement rules that have to agree between server and client live in [`movement`],
+//! [`navigation`] and [`collision`], and animation timing in [`animation`]. Proximity queries
+//! go through the grid in [`spatial`]. The ground of the world is a [`Tilemap`], see
+//! [`tilemap`]. Chat channels and limits are in [`chat`].
+//!
+//! One-off events are declared with `networked_events!` and implement [`NetworkEvent`].
+//! Global state lives in resources declared with `networked_resources!`, which implement
+//! [`NetworkResource`].
+
+pub mod animation;
+pub mod chat;
+pub mod collision;
+pub mod entity_mapping;
+pub mod movement;
+pub mod navigation;
+pub mod quantize;
+pub mod spatial;
+pub mod tilemap;
 
 use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
 use bevy::utils::HashMap;
-use messages::KindId;
+use messages::{Delivery, KindId, Tick, VisibilityRule};
+use quantize::{Position, Quantized, Scale};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::any::TypeId;
+use tilemap::Tile;
 
 macro_rules! networked {
     (@extract_ident $map1:ident $map2:ident) => {};
-    (@extract_ident $map1:ident $map2:ident $id:expr => $c:ident { $($field:ident: $type:ty),* $(,)?} $($tail:tt)*) => {
+    (@extract_ident $map1:ident $map2:ident $(#[delivery($delivery:ident)])? $(#[visibility($visibility:ident)])? $id:literal => $c:ident { $($(#[$meta:meta])* $field:ident: $type:ty),* $(,)?} $($tail:tt)*) => {
         networked!(@insert_mappings $map1 $map2 $c);
         networked!(@extract_ident $map1 $map2 $($tail)*);
     };
-    (@extract_ident $map1:ident $map2:ident $id:expr => $c:ident($($type:ty),* $(,)?) $($tail:tt)*) => {
+    (@extract_ident $map1:ident $map2:ident $(#[delivery($delivery:ident)])? $(#[visibility($visibility:ident)])? $id:literal => $c:ident($($type:ty),* $(,)?) $($tail:tt)*) => {
         networked!(@insert_mappings $map1 $map2 $c);
-        networked!(@extract_ident $map1 $map2 $($tail:tt)*);
+        networked!(@extract_ident $map1 $map2 $($tail)*);
     };
     (@insert_mappings $map1:ident $map2:ident $c:ident) => {
         $map1.insert($c::KIND_ID, std::any::TypeId::of::<$c>());
@@ -25,28 +55,30 @@ macro_rules! networked {
     };
     (@component) => {};
     // struct
-    (@component $id:expr => $c:ident { $($field:ident: $type:ty),* $(,)?} $($tail:tt)*) => {
-        #[derive(Component, Serialize, Deserialize, Default, Reflect)]
+    (@component $(#[delivery($delivery:ident)])? $(#[visibility($visibility:ident)])? $id:literal => $c:ident { $($(#[$meta:meta])* $field:ident: $type:ty),* $(,)?} $($tail:tt)*) => {
+        #[derive(Component, Serialize, Deserialize, Default, Clone, Reflect)]
         #[reflect(Component, Serialize, Deserialize)]
         pub struct $c {
-            $(pub $field: $type),*
+            $($(#[$meta])* pub $field: $type),*
         }
 
-        networked!(@impl_kind_id $c $id);
+        networked!(@impl_kind_id $c $id [$($delivery)?] [$($visibility)?]);
         networked!(@component $($tail)*);
     };
     // tuple struct
-    (@component $id:expr => $c:ident($($type:ty),* $(,)?) $($tail:tt)*) => {
-        #[derive(Component, Serialize, Deserialize, Default, Reflect)]
+    (@component $(#[delivery($delivery:ident)])? $(#[visibility($visibility:ident)])? $id:literal => $c:ident($($type:ty),* $(,)?) $($tail:tt)*) => {
+        #[derive(Component, Serialize, Deserialize, Default, Clone, Reflect)]
         #[reflect(Component, Serialize, Deserialize)]
-        pub struct $c ($($type),*);
+        pub struct $c ($(pub $type),*);
 
-        networked!(@impl_kind_id $c $id);
+        networked!(@impl_kind_id $c $id [$($delivery)?] [$($visibility)?]);
         networked!(@component $($tail)*);
     };
-    (@impl_kind_id $c:ident $id:expr) => {
+    (@impl_kind_id $c:ident $id:literal [$($delivery:ident)?] [$($visibility:ident)?]) => {
         impl KindId for $c {
             const KIND_ID: u16 = $id;
+            $(const DELIVERY: Delivery = Delivery::$delivery;)?
+            $(const VISIBILITY: VisibilityRule = VisibilityRule::$visibility;)?
         }
     };
     ($($tail:tt)+) => {
@@ -63,14 +95,134 @@ macro_rules! networked {
     };
 }
 
+macro_rules! networked_events {
+    () => {};
+    ($id:literal => $e:ident { $($(#[$meta:meta])* $field:ident: $type:ty),* $(,)?} $($tail:tt)*) => {
+        #[derive(Debug, Clone, Serialize, Deserialize, Reflect)]
+        pub struct $e {
+            $($(#[$meta])* pub $field: $type),*
+        }
+
+        impl KindId for $e {
+            const KIND_ID: u16 = $id;
+        }
+
+        impl NetworkEvent for $e {}
+
+        networked_events!($($tail)*);
+    };
+}
+
+macro_rules! networked_resources {
+    () => {};
+    ($id:literal => $r:ident { $($(#[$meta:meta])* $field:ident: $type:ty),* $(,)?} $($tail:tt)*) => {
+        #[derive(Debug, Clone, Serialize, Deserialize, Default, Reflect)]
+        pub struct $r {
+            $($(#[$meta])* pub $field: $type),*
+        }
+
+        impl KindId for $r {
+            const KIND_ID: u16 = $id;
+        }
+
+        impl NetworkResource for $r {}
+
+        networked_resources!($($tail)*);
+    };
+}
+
+/// Anything that is sent over the network by kind id and not attached to an entity
+pub trait NetworkKind:
+    KindId + Serialize + DeserializeOwned + Reflect + Clone + Send + Sync + 'static
+{
+}
+
+impl<T> NetworkKind for T where
+    T: KindId + Serialize + DeserializeOwned + Reflect + Clone + Send + Sync + 'static
+{
+}
+
+/// One-off events sent between the server and clients, declared with `networked_events!`
+///
+/// Events share the kind id space with components. Entity references in them are remapped the
+/// same way as in components.
+pub trait NetworkEvent: NetworkKind {}
+
 networked! {
-    100 => NSprite {
-        sprite_index: u32,
-    }
+    #[delivery(Unreliable)]
     200 => NTransform {
+        #[serde(with = "Quantized::<Position>")]
         translation: Vec2,
+        #[serde(with = "Quantized::<Scale>")]
         scale: Vec2
     }
+    300 => NParent {
+        parent: Option<Entity>,
+    }
+    // the entity this client controls
+    #[visibility(OwnerOnly)]
+    400 => NControlled {}
+    // clients play the animation themselves, starting at `start_tick`, and the server only
+    // sends it to players nearby
+    #[visibility(Filtered)]
+    500 => NAnimation {
+        name: String,
+        start_tick: Tick,
+    }
+    // circle that other colliders are kept out of
+    600 => NCollider {
+        radius: f32,
+    }
+    // colliders that never move
+    700 => NObstacle {}
+}
+
+/// Resources replicated from the server to clients, declared with `networked_resources!`
+///
+/// Resources share the kind id space with components and events. They are sent whenever they
+/// change and are part of the snapshot joining players get.
+pub trait NetworkResource: NetworkKind {}
+
+networked_events! {
+    // client to server
+    1000 => UseAbility {
+        ability: u32,
+        target: Option<Entity>,
+    }
+    // a chat line, or a command if it starts with `/`, see `chat`
+    1200 => SendChat {
+        text: String,
+    }
+    // server to clients
+    1100 => PlayEffect {
+        effect: u32,
+        entity: Option<Entity>,
+    }
+    // `channel` is a `chat::ChatChannel`
+    1300 => ChatMessage {
+        channel: u8,
+        from: String,
+        text: String,
+    }
+}
+
+networked_resources! {
+    2000 => WorldSettings {
+        /// Size of the playable area, centered on the origin
+        size: Vec2,
+    }
+    // the ground the world is made of, see `tilemap`
+    2100 => Tilemap {
+        /// Image the tile sprites are cut from, `tileset_columns` tiles wide
+        tileset: String,
+        tileset_columns: u32,
+        /// Width and height of a tile, both in world units and in tileset pixels
+        tile_size: f32,
+        width: u32,
+        height: u32,
+        /// Tiles row by row, the top row first
+        tiles: Vec<Tile>,
+    }
 }
 
 impl From<Transform> for NTransform {
@@ -83,7 +235,8 @@ impl From<Transform> for NTransform {
 }
 
 impl NTransform {
+    /// The z scale is 1, so children of the transform keep their depth
     pub fn as_transform(&self) -> Transform {
-        Transform::from_translation(self.translation.extend(0.)).with_scale(self.scale.extend(0.))
+        Transform::from_translation(self.translation.extend(0.)).with_scale(self.scale.extend(1.))
     }
 }
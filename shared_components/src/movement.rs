@@ -0,0 +1,236 @@
+//! Movement rules shared by the server simulation and client prediction
+//!
+//! Movement advances in fixed steps of one server tick: the server runs [`move_to_targets`]
+//! once per tick, and the client's prediction calls [`follow_path`] once per estimated server
+//! tick. Both use the same [`MovementSettings`] and plan a path once per target on the same
+//! [`NavGrid`], which the prediction keeps with the input and replays, so it walks the path the
+//! server walks. Other movers pushing the entity around aren't predicted, and an input the
+//! server applies at another tick than predicted starts from elsewhere, so corrections still
+//! happen and are expected.
+
+use crate::navigation::{NavGrid, Path};
+use bevy::prelude::*;
+
+/// Where an entity is walking to, removed once it arrives or gets as close as it can
+#[derive(Component, Clone, Copy, Debug)]
+pub struct MoveTarget(pub Vec2);
+
+pub struct MovementSettings {
+    /// Distance covered per tick
+    pub speed: f32,
+    /// Entities closer than this to their target have arrived
+    pub arrival_distance: f32,
+}
+
+impl Default for MovementSettings {
+    fn default() -> Self {
+        MovementSettings {
+            speed: 1.,
+            arrival_distance: 0.01,
+        }
+    }
+}
+
+/// Position after one tick of moving towards `target`
+pub fn step(translation: Vec2, target: Vec2, settings: &MovementSettings) -> Vec2 {
+    let distance = translation.distance(target).min(settings.speed);
+    translation + (target - translation).normalize_or_zero() * distance
+}
+
+pub fn has_arrived(translation: Vec2, target: Vec2, settings: &MovementSettings) -> bool {
+    translation.distance(target) <= settings.arrival_distance
+}
+
+/// Position after one tick of walking along `path`, dropping the waypoints it reaches
+///
+/// Reaching a waypoint doesn't cost the rest of the step, it goes on towards the next one.
+pub fn follow_path(mut translation: Vec2, path: &mut Path, settings: &MovementSettings) -> Vec2 {
+    let mut distance = settings.speed;
+    while let Some(waypoint) = path.0.front().copied() {
+        let to_waypoint = translation.distance(waypoint);
+        translation += (waypoint - translation).normalize_or_zero() * to_waypoint.min(distance);
+        if !has_arrived(translation, waypoint, settings) {
+            break;
+        }
+        path.0.pop_front();
+        distance -= to_waypoint;
+        if distance <= 0. {
+            break;
+        }
+    }
+    translation
+}
+
+type Walkers<'a> = (
+    Entity,
+    &'a mut Transform,
+    &'a MoveTarget,
+    ChangeTrackers<MoveTarget>,
+    Option<&'a mut Path>,
+);
+
+/// Moves every entity one tick along the path to its [`MoveTarget`]
+///
+/// A new target gets a new path, planned from where the entity is.
+pub fn move_to_targets(
+    mut commands: Commands,
+    settings: Res<MovementSettings>,
+    mut nav: ResMut<NavGrid>,
+    mut entities: Query<Walkers>,
+) {
+    for (entity, mut transform, target, target_tracker, path) in entities.iter_mut() {
+        let position = transform.translation.truncate();
+        let (translation, finished) = match path {
+            Some(mut path) if !target_tracker.is_changed() => {
+                let translation = follow_path(position, &mut path, &settings);
+                (translation, path.is_finished())
+            }
+            _ => {
+                let mut path = nav.find_path(position, target.0);
+                let translation = follow_path(position, &mut path, &settings);
+                let finished = path.is_finished();
+                if !finished {
+                    commands.entity(entity).insert(path);
+                }
+                (translation, finished)
+            }
+        };
+        transform.translation = translation.extend(transform.translation.z);
+
+        if finished {
+            commands
+                .entity(entity)
+                .remove::<MoveTarget>()
+                .remove::<Path>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    fn settings() -> MovementSettings {
+        MovementSettings {
+            speed: 2.,
+            arrival_distance: 0.01,
+        }
+    }
+
+    /// A world with a round obstacle in the middle, which paths across have to go around
+    fn nav_grid() -> NavGrid {
+        let mut nav = NavGrid::default();
+        nav.build(
+            Vec2::new(400., 400.),
+            [(Vec2::ZERO, 40.)],
+            std::iter::empty::<(Vec2, Vec2)>(),
+        );
+        nav
+    }
+
+    #[test]
+    fn step_is_capped_at_speed() {
+        let settings = settings();
+        assert_eq!(
+            step(Vec2::ZERO, Vec2::new(10., 0.), &settings),
+            Vec2::new(2., 0.)
+        );
+        assert_eq!(
+            step(Vec2::ZERO, Vec2::new(-3., -4.), &settings),
+            Vec2::new(-1.2, -1.6)
+        );
+        // a target closer than a step is reached, not overshot
+        assert_eq!(
+            step(Vec2::ZERO, Vec2::new(0., 1.5), &settings),
+            Vec2::new(0., 1.5)
+        );
+        assert_eq!(step(Vec2::ONE, Vec2::ONE, &settings), Vec2::ONE);
+    }
+
+    #[test]
+    fn arrival_is_within_the_arrival_distance() {
+        let settings = settings();
+        assert!(has_arrived(Vec2::ZERO, Vec2::ZERO, &settings));
+        assert!(has_arrived(Vec2::ZERO, Vec2::new(0.01, 0.), &settings));
+        assert!(!has_arrived(Vec2::ZERO, Vec2::new(0.02, 0.), &settings));
+    }
+
+    #[test]
+    fn following_a_path_arrives_and_finishes_it() {
+        let settings = settings();
+        let mut path = Path(VecDeque::from([Vec2::new(3., 0.)]));
+
+        let translation = follow_path(Vec2::ZERO, &mut path, &settings);
+        assert_eq!(translation, Vec2::new(2., 0.));
+        assert!(!path.is_finished());
+
+        let translation = follow_path(translation, &mut path, &settings);
+        assert_eq!(translation, Vec2::new(3., 0.));
+        assert!(path.is_finished());
+
+        // nothing left to follow
+        assert_eq!(follow_path(translation, &mut path, &settings), translation);
+    }
+
+    #[test]
+    fn the_rest_of_a_step_carries_past_a_waypoint() {
+        let mut path = Path(VecDeque::from([Vec2::new(1., 0.), Vec2::new(1., 5.)]));
+
+        let translation = follow_path(Vec2::ZERO, &mut path, &settings());
+        assert_eq!(translation, Vec2::new(1., 1.));
+        assert_eq!(path.0, VecDeque::from([Vec2::new(1., 5.)]));
+    }
+
+    #[test]
+    fn a_step_can_pass_several_waypoints() {
+        let mut path = Path(VecDeque::from([
+            Vec2::new(0.5, 0.),
+            Vec2::new(0.5, 0.5),
+            Vec2::new(5., 0.5),
+        ]));
+
+        let translation = follow_path(Vec2::ZERO, &mut path, &settings());
+        assert_eq!(translation, Vec2::new(1.5, 0.5));
+        assert_eq!(path.0.len(), 1);
+    }
+
+    #[test]
+    fn prediction_walks_where_the_server_does() {
+        let start = Vec2::new(-150., 10.);
+        let target = Vec2::new(150., -10.);
+
+        // the server, running its movement system once per tick
+        let mut app = App::new();
+        app.insert_resource(settings())
+            .insert_resource(nav_grid())
+            .add_system(move_to_targets);
+        let walker = app
+            .world
+            .spawn()
+            .insert(Transform::from_translation(start.extend(0.)))
+            .insert(MoveTarget(target))
+            .id();
+
+        // a client predicting the same input
+        let settings = settings();
+        let mut nav = nav_grid();
+        let mut path = nav.find_path(start, target);
+        assert!(path.0.len() > 1, "the path should go around the obstacle");
+        let mut predicted = start;
+
+        for tick in 0..1000 {
+            app.update();
+            predicted = follow_path(predicted, &mut path, &settings);
+
+            let server = app.world.get::<Transform>(walker).unwrap().translation;
+            assert_eq!(server.truncate(), predicted, "apart at tick {}", tick);
+            if path.is_finished() {
+                break;
+            }
+        }
+        assert!(path.is_finished());
+        assert!(app.world.get::<MoveTarget>(walker).is_none());
+        assert!(predicted.distance(target) <= settings.arrival_distance);
+    }
+}
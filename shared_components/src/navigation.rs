@@ -0,0 +1,505 @@
+//! Pathfinding around static obstacles
+//!
+//! The [`NavGrid`] covers the world with square cells and marks the ones too close to an
+//! obstacle or a solid tile for a creature to stand in. Paths are found with A* over the cells,
+//! eight ways with no cutting of blocked corners, then smoothed by skipping every waypoint that
+//! can be seen from the one before it. Cell paths are cached per start and goal cell until the
+//! grid changes.
+//!
+//! The grid is rebuilt from the replicated obstacles, [`Tilemap`] and [`WorldSettings`] by
+//! [`update_nav_grid`], on the server and on clients alike, and the search has no randomness
+//! or hash ordering in it, so client prediction walks the same paths as the server.
+
+use crate::{NCollider, NObstacle, NTransform, Tilemap, WorldSettings};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::sync::Arc;
+
+/// Cost of a straight and a diagonal step between cells
+const STRAIGHT_COST: u32 = 10;
+const DIAGONAL_COST: u32 = 14;
+/// The cache is cleared when it holds more paths than this
+const MAX_CACHED_PATHS: usize = 1024;
+
+/// Waypoints an entity walks along, the next one first
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+pub struct Path(pub VecDeque<Vec2>);
+
+impl Path {
+    pub fn is_finished(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+pub struct NavGrid {
+    cell_size: f32,
+    /// Obstacles are grown by this, so cells that are free fit a creature
+    agent_radius: f32,
+    /// World position of the corner of cell (0, 0)
+    origin: Vec2,
+    width: u32,
+    height: u32,
+    blocked: Vec<bool>,
+    cache: HashMap<(u32, u32), Arc<[u32]>>,
+}
+
+impl Default for NavGrid {
+    fn default() -> Self {
+        NavGrid::new(16., 8.)
+    }
+}
+
+impl NavGrid {
+    /// An empty grid, nothing can be found on it until it is built
+    pub fn new(cell_size: f32, agent_radius: f32) -> Self {
+        assert!(cell_size > 0., "cell size must be positive");
+        NavGrid {
+            cell_size,
+            agent_radius,
+            origin: Vec2::ZERO,
+            width: 0,
+            height: 0,
+            blocked: Vec::new(),
+            cache: HashMap::default(),
+        }
+    }
+
+    /// Covers a world of `size` around the origin, blocking cells near obstacles and near the
+    /// solid rectangles, given by their corners
+    pub fn build(
+        &mut self,
+        size: Vec2,
+        obstacles: impl IntoIterator<Item = (Vec2, f32)>,
+        solids: impl IntoIterator<Item = (Vec2, Vec2)>,
+    ) {
+        self.origin = -size / 2.;
+        self.width = (size.x / self.cell_size).ceil().max(1.) as u32;
+        self.height = (size.y / self.cell_size).ceil().max(1.) as u32;
+        self.blocked = vec![false; (self.width * self.height) as usize];
+        self.cache.clear();
+
+        for (center, radius) in obstacles {
+            let reach = radius + self.agent_radius;
+            let min = self.clamped_cell(center - reach);
+            let max = self.clamped_cell(center + reach);
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let cell = UVec2::new(x, y);
+                    if self.center(cell).distance(center) < reach {
+                        let index = self.index(cell);
+                        self.blocked[index as usize] = true;
+                    }
+                }
+            }
+        }
+
+        for (solid_min, solid_max) in solids {
+            let (reach_min, reach_max) =
+                (solid_min - self.agent_radius, solid_max + self.agent_radius);
+            let min = self.clamped_cell(reach_min);
+            let max = self.clamped_cell(reach_max);
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let cell = UVec2::new(x, y);
+                    let center = self.center(cell);
+                    if center.cmpgt(reach_min).all() && center.cmplt(reach_max).all() {
+                        let index = self.index(cell);
+                        self.blocked[index as usize] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_built(&self) -> bool {
+        !self.blocked.is_empty()
+    }
+
+    fn clamped_cell(&self, position: Vec2) -> UVec2 {
+        let cell = ((position - self.origin) / self.cell_size).floor();
+        cell.clamp(
+            Vec2::ZERO,
+            Vec2::new(self.width as f32 - 1., self.height as f32 - 1.),
+        )
+        .as_uvec2()
+    }
+
+    fn center(&self, cell: UVec2) -> Vec2 {
+        self.origin + (cell.as_vec2() + 0.5) * self.cell_size
+    }
+
+    fn index(&self, cell: UVec2) -> u32 {
+        cell.y * self.width + cell.x
+    }
+
+    fn cell_at(&self, index: u32) -> UVec2 {
+        UVec2::new(index % self.width, index / self.width)
+    }
+
+    fn is_blocked(&self, x: i64, y: i64) -> bool {
+        x < 0
+            || y < 0
+            || x >= self.width as i64
+            || y >= self.height as i64
+            || self.blocked[(y * self.width as i64 + x) as usize]
+    }
+
+    /// Whether a straight walk from `from` to `to` stays on free cells
+    ///
+    /// The cell `from` is in doesn't count, so entities pushed against an obstacle can walk away.
+    pub fn line_walkable(&self, from: Vec2, to: Vec2) -> bool {
+        if !self.is_built() {
+            return true;
+        }
+        let start = self.clamped_cell(from);
+        let steps = (from.distance(to) / (self.cell_size / 4.)).ceil() as u32;
+        (1..=steps).all(|i| {
+            let cell = self.clamped_cell(from.lerp(to, i as f32 / steps as f32));
+            cell == start || !self.blocked[self.index(cell) as usize]
+        })
+    }
+
+    /// Waypoints from `from` to `to`, or to the closest reachable point if `to` can't be reached
+    pub fn find_path(&mut self, from: Vec2, to: Vec2) -> Path {
+        if self.line_walkable(from, to) {
+            return Path(VecDeque::from([to]));
+        }
+
+        let start = self.index(self.clamped_cell(from));
+        let goal = self.index(self.clamped_cell(to));
+        let cells = match self.cache.get(&(start, goal)) {
+            Some(cells) => cells.clone(),
+            None => {
+                let cells: Arc<[u32]> = self.search(start, goal).into();
+                if self.cache.len() >= MAX_CACHED_PATHS {
+                    self.cache.clear();
+                }
+                self.cache.insert((start, goal), cells.clone());
+                cells
+            }
+        };
+
+        // nowhere to go from the start cell
+        if cells.len() < 2 {
+            return Path(VecDeque::from([to]));
+        }
+
+        let mut points: Vec<Vec2> = cells[1..]
+            .iter()
+            .map(|index| self.center(self.cell_at(*index)))
+            .collect();
+        if cells.last() == Some(&goal) {
+            *points.last_mut().unwrap() = to;
+        }
+        Path(self.smooth(from, &points))
+    }
+
+    /// Keeps only the points that can't be seen from the one kept before them
+    fn smooth(&self, from: Vec2, points: &[Vec2]) -> VecDeque<Vec2> {
+        let mut smoothed = VecDeque::new();
+        let mut anchor = from;
+        let mut next = 0;
+        while next < points.len() {
+            let furthest = (next..points.len())
+                .rev()
+                .find(|i| self.line_walkable(anchor, points[*i]))
+                .unwrap_or(next);
+            smoothed.push_back(points[furthest]);
+            anchor = points[furthest];
+            next = furthest + 1;
+        }
+        smoothed
+    }
+
+    /// Cell indices from `start` to `goal`, or to the reachable cell closest to it
+    fn search(&self, start: u32, goal: u32) -> Vec<u32> {
+        let goal_cell = self.cell_at(goal);
+        let heuristic = |index: u32| {
+            let cell = self.cell_at(index);
+            let dx = cell.x.abs_diff(goal_cell.x);
+            let dy = cell.y.abs_diff(goal_cell.y);
+            STRAIGHT_COST * dx.max(dy) + (DIAGONAL_COST - STRAIGHT_COST) * dx.min(dy)
+        };
+
+        let mut cost = vec![u32::MAX; self.blocked.len()];
+        let mut came_from = vec![u32::MAX; self.blocked.len()];
+        let mut open = BinaryHeap::new();
+        cost[start as usize] = 0;
+        open.push(Reverse((heuristic(start), heuristic(start), start)));
+        let mut closest = (heuristic(start), start);
+
+        while let Some(Reverse((estimate, h, index))) = open.pop() {
+            // already reached more cheaply since this was queued
+            if cost[index as usize] + h < estimate {
+                continue;
+            }
+            if index == goal {
+                closest = (0, goal);
+                break;
+            }
+            if h < closest.0 {
+                closest = (h, index);
+            }
+
+            let cell = self.cell_at(index);
+            let (x, y) = (cell.x as i64, cell.y as i64);
+            for (dx, dy) in [
+                (1, 0),
+                (-1, 0),
+                (0, 1),
+                (0, -1),
+                (1, 1),
+                (1, -1),
+                (-1, 1),
+                (-1, -1),
+            ] {
+                if self.is_blocked(x + dx, y + dy) {
+                    continue;
+                }
+                let diagonal = dx != 0 && dy != 0;
+                if diagonal && (self.is_blocked(x + dx, y) || self.is_blocked(x, y + dy)) {
+                    continue;
+                }
+
+                let neighbour = ((y + dy) * self.width as i64 + x + dx) as u32;
+                let step = if diagonal {
+                    DIAGONAL_COST
+                } else {
+                    STRAIGHT_COST
+                };
+                let new_cost = cost[index as usize] + step;
+                if new_cost < cost[neighbour as usize] {
+                    cost[neighbour as usize] = new_cost;
+                    came_from[neighbour as usize] = index;
+                    let h = heuristic(neighbour);
+                    open.push(Reverse((new_cost + h, h, neighbour)));
+                }
+            }
+        }
+
+        let mut cells = vec![closest.1];
+        while let Some(&previous) = came_from.get(*cells.last().unwrap() as usize) {
+            if previous == u32::MAX {
+                break;
+            }
+            cells.push(previous);
+        }
+        cells.reverse();
+        cells
+    }
+}
+
+type ObstacleChanged = (
+    With<NObstacle>,
+    Or<(Changed<NTransform>, Changed<NCollider>)>,
+);
+
+/// Rebuilds the grid when the world, its map or its obstacles change
+pub fn update_nav_grid(
+    mut nav: ResMut<NavGrid>,
+    world: Option<Res<WorldSettings>>,
+    map: Option<Res<Tilemap>>,
+    changed: Query<(), ObstacleChanged>,
+    removed: RemovedComponents<NObstacle>,
+    obstacles: Query<(&NTransform, &NCollider), With<NObstacle>>,
+) {
+    // clients get the world settings some time after starting
+    let world = match world {
+        Some(world) => world,
+        None => return,
+    };
+    let map_changed = map.as_ref().map_or(false, |map| map.is_changed());
+    if !world.is_changed() && !map_changed && changed.is_empty() && removed.iter().next().is_none()
+    {
+        return;
+    }
+
+    nav.build(
+        world.size,
+        obstacles
+            .iter()
+            .map(|(n_transform, collider)| (n_transform.translation, collider.radius)),
+        map.iter().flat_map(|map| map.all_solid_rects()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CELL_SIZE: f32 = 10.;
+
+    /// A 100 by 100 world of 10 by 10 cells, with obstacles grown by nothing so cells are
+    /// blocked exactly where the solids are
+    fn nav_grid(solids: &[(Vec2, Vec2)]) -> NavGrid {
+        let mut nav = NavGrid::new(CELL_SIZE, 0.);
+        nav.build(
+            Vec2::splat(100.),
+            std::iter::empty::<(Vec2, f32)>(),
+            solids.iter().copied(),
+        );
+        nav
+    }
+
+    /// A wall across the middle with a gap at the top
+    fn wall() -> (Vec2, Vec2) {
+        (Vec2::new(-10., -50.), Vec2::new(10., 30.))
+    }
+
+    fn is_free(nav: &NavGrid, position: Vec2) -> bool {
+        let cell = nav.clamped_cell(position);
+        !nav.is_blocked(cell.x as i64, cell.y as i64)
+    }
+
+    /// Every leg of the path, from `from` on, stays on free cells
+    fn assert_walkable(nav: &NavGrid, from: Vec2, path: &Path) {
+        let mut previous = from;
+        for waypoint in path.0.iter() {
+            assert!(
+                nav.line_walkable(previous, *waypoint),
+                "{} to {} crosses a blocked cell in {:?}",
+                previous,
+                waypoint,
+                path
+            );
+            previous = *waypoint;
+        }
+    }
+
+    #[test]
+    fn clear_line_is_a_single_waypoint() {
+        let mut nav = nav_grid(&[wall()]);
+        let (from, to) = (Vec2::new(-30., 40.), Vec2::new(30., 35.));
+        assert_eq!(nav.find_path(from, to), Path(VecDeque::from([to])));
+        assert!(nav.cache.is_empty());
+
+        // a grid that isn't built yet has nothing in the way
+        let mut nav = NavGrid::new(CELL_SIZE, 0.);
+        let to = Vec2::new(1000., 0.);
+        assert_eq!(nav.find_path(Vec2::ZERO, to), Path(VecDeque::from([to])));
+    }
+
+    #[test]
+    fn paths_go_around_obstacles() {
+        let mut nav = nav_grid(&[wall()]);
+        let (from, to) = (Vec2::new(-30., -30.), Vec2::new(30., -30.));
+        let path = nav.find_path(from, to);
+
+        assert!(path.0.len() > 1, "{:?} goes through the wall", path);
+        assert_eq!(path.0.back(), Some(&to));
+        assert!(path.0.iter().all(|waypoint| is_free(&nav, *waypoint)));
+        assert!(path.0.iter().any(|waypoint| waypoint.y > 30.));
+        assert_walkable(&nav, from, &path);
+    }
+
+    #[test]
+    fn smoothing_keeps_only_corners() {
+        let mut nav = nav_grid(&[wall()]);
+        let path = nav.find_path(Vec2::new(-30., -30.), Vec2::new(30., -30.));
+
+        // up to the gap, through it and down again, rather than a waypoint per cell
+        assert!(path.0.len() <= 3, "{:?} wasn't smoothed", path);
+    }
+
+    #[test]
+    fn unreachable_goal_ends_at_the_closest_reachable_cell() {
+        let solid = (Vec2::new(10., -20.), Vec2::new(40., 20.));
+        let mut nav = nav_grid(&[solid]);
+        let (from, to) = (Vec2::new(-30., 0.), Vec2::new(25., 0.));
+        let path = nav.find_path(from, to);
+
+        let end = *path.0.back().unwrap();
+        assert_ne!(end, to);
+        assert!(is_free(&nav, end));
+        // right next to the solid the goal is in
+        assert!(
+            end.distance(to) <= 2.5 * CELL_SIZE,
+            "{} is far from {}",
+            end,
+            to
+        );
+        assert_walkable(&nav, from, &path);
+    }
+
+    #[test]
+    fn diagonal_steps_never_cut_blocked_corners() {
+        // two blocked cells touching at their corners, at the origin
+        let nav = nav_grid(&[
+            (Vec2::new(0., -10.), Vec2::new(10., 0.)),
+            (Vec2::new(-10., 0.), Vec2::new(0., 10.)),
+        ]);
+        let start = nav.index(UVec2::new(4, 4));
+        let goal = nav.index(UVec2::new(5, 5));
+        let cells = nav.search(start, goal);
+
+        assert_eq!(cells.first(), Some(&start));
+        assert_eq!(cells.last(), Some(&goal));
+        assert!(cells.len() > 2, "{:?} squeezes between the corners", cells);
+        for step in cells.windows(2) {
+            let (a, b) = (nav.cell_at(step[0]), nav.cell_at(step[1]));
+            let (x, y) = (a.x as i64, a.y as i64);
+            let (dx, dy) = (b.x as i64 - x, b.y as i64 - y);
+            assert!(dx.abs() <= 1 && dy.abs() <= 1);
+            assert!(!nav.is_blocked(x + dx, y + dy));
+            if dx != 0 && dy != 0 {
+                assert!(!nav.is_blocked(x + dx, y) && !nav.is_blocked(x, y + dy));
+            }
+        }
+    }
+
+    #[test]
+    fn blocked_start_can_walk_out() {
+        let mut nav = nav_grid(&[wall()]);
+        // inside the wall, pushed there by something
+        let (from, to) = (Vec2::new(5., -30.), Vec2::new(30., -30.));
+        assert!(!is_free(&nav, from));
+        assert_eq!(nav.find_path(from, to), Path(VecDeque::from([to])));
+
+        let to = Vec2::new(-30., -30.);
+        let path = nav.find_path(from, to);
+        assert_eq!(path.0.back(), Some(&to));
+        assert!(path.0.iter().all(|waypoint| is_free(&nav, *waypoint)));
+    }
+
+    #[test]
+    fn enclosed_start_stays_put_and_heads_for_the_goal() {
+        // a ring of solids around the cell at the origin
+        let mut nav = nav_grid(&[
+            (Vec2::new(-10., -10.), Vec2::new(20., 0.)),
+            (Vec2::new(-10., 10.), Vec2::new(20., 20.)),
+            (Vec2::new(-10., 0.), Vec2::new(0., 10.)),
+            (Vec2::new(10., 0.), Vec2::new(20., 10.)),
+        ]);
+        let to = Vec2::new(-40., -40.);
+        assert_eq!(
+            nav.find_path(Vec2::new(5., 5.), to),
+            Path(VecDeque::from([to]))
+        );
+    }
+
+    #[test]
+    fn paths_are_cached_until_the_grid_is_built_again() {
+        let mut nav = nav_grid(&[wall()]);
+        let (from, to) = (Vec2::new(-30., -30.), Vec2::new(30., -30.));
+        let path = nav.find_path(from, to);
+        assert_eq!(nav.cache.len(), 1);
+        assert_eq!(nav.find_path(from, to), path);
+        assert_eq!(nav.cache.len(), 1);
+
+        // the gap moves to the bottom, so the cached path would now cross the wall
+        let solid = (Vec2::new(-10., -30.), Vec2::new(10., 50.));
+        nav.build(
+            Vec2::splat(100.),
+            std::iter::empty::<(Vec2, f32)>(),
+            [solid],
+        );
+        assert!(nav.cache.is_empty());
+
+        let moved = nav.find_path(from, to);
+        assert_ne!(moved, path);
+        assert!(moved.0.iter().all(|waypoint| waypoint.y <= -30.));
+        assert_walkable(&nav, from, &moved);
+    }
+}
@@ -0,0 +1,228 @@
+//! Fixed-point quantization for networked float fields
+//!
+//! A field opts in with `#[serde(with = "Quantized::<Spec>")]`, where `Spec` implements
+//! [`Quantization`]. Each axis of the value is clamped to the spec's bounds, mapped to an
+//! integer step and all axes are bit-packed into a single `u64`, which postcard writes as a
+//! varint. A 2D position with 16 bits per axis takes at most 5 bytes on the wire.
+
+use bevy::math::{Vec2, Vec3};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::marker::PhantomData;
+
+/// Bounds and precision of a quantized value
+pub trait Quantization {
+    /// Smallest representable value, anything below is clamped
+    const MIN: f32;
+    /// Largest representable value, anything above is clamped
+    const MAX: f32;
+    /// Distance between two neighbouring representable values
+    const PRECISION: f32;
+
+    /// Largest step value, `MIN` maps to 0 and `MAX` to this
+    fn max_step() -> u64 {
+        ((Self::MAX - Self::MIN) / Self::PRECISION).round() as u64
+    }
+
+    /// Number of bits needed for one axis
+    fn bits() -> u32 {
+        u64::BITS - Self::max_step().leading_zeros()
+    }
+
+    fn quantize(value: f32) -> u64 {
+        let step = ((value.clamp(Self::MIN, Self::MAX) - Self::MIN) / Self::PRECISION).round();
+        (step as u64).min(Self::max_step())
+    }
+
+    fn dequantize(step: u64) -> f32 {
+        Self::MIN + step as f32 * Self::PRECISION
+    }
+}
+
+/// World space positions, ±2048 units at 1/8 unit precision (16 bits per axis)
+pub struct Position;
+
+impl Quantization for Position {
+    const MIN: f32 = -2048.;
+    const MAX: f32 = 2048.;
+    const PRECISION: f32 = 1. / 8.;
+}
+
+/// Scale factors, 0 to 16 at 1/64 precision (11 bits per axis)
+pub struct Scale;
+
+impl Quantization for Scale {
+    const MIN: f32 = 0.;
+    const MAX: f32 = 16.;
+    const PRECISION: f32 = 1. / 64.;
+}
+
+/// Values that can be bit-packed with a [`Quantization`]
+pub trait Quantize: Sized {
+    /// Number of float axes packed together
+    const AXES: u32;
+
+    fn pack<Q: Quantization>(&self) -> u64;
+    fn unpack<Q: Quantization>(packed: u64) -> Self;
+}
+
+fn pack_axes<Q: Quantization>(axes: &[f32]) -> u64 {
+    let bits = Q::bits();
+    axes.iter()
+        .fold(0, |packed, axis| packed << bits | Q::quantize(*axis))
+}
+
+fn unpack_axes<Q: Quantization, const N: usize>(mut packed: u64) -> [f32; N] {
+    let bits = Q::bits();
+    let mask = (1 << bits) - 1;
+    let mut axes = [0.; N];
+
+    // axes were packed first to last, so the last one is in the lowest bits
+    for axis in axes.iter_mut().rev() {
+        *axis = Q::dequantize(packed & mask);
+        packed >>= bits;
+    }
+
+    axes
+}
+
+impl Quantize for f32 {
+    const AXES: u32 = 1;
+
+    fn pack<Q: Quantization>(&self) -> u64 {
+        pack_axes::<Q>(&[*self])
+    }
+
+    fn unpack<Q: Quantization>(packed: u64) -> Self {
+        let [value] = unpack_axes::<Q, 1>(packed);
+        value
+    }
+}
+
+impl Quantize for Vec2 {
+    const AXES: u32 = 2;
+
+    fn pack<Q: Quantization>(&self) -> u64 {
+        pack_axes::<Q>(&self.to_array())
+    }
+
+    fn unpack<Q: Quantization>(packed: u64) -> Self {
+        Vec2::from_array(unpack_axes::<Q, 2>(packed))
+    }
+}
+
+impl Quantize for Vec3 {
+    const AXES: u32 = 3;
+
+    fn pack<Q: Quantization>(&self) -> u64 {
+        pack_axes::<Q>(&self.to_array())
+    }
+
+    fn unpack<Q: Quantization>(packed: u64) -> Self {
+        Vec3::from_array(unpack_axes::<Q, 3>(packed))
+    }
+}
+
+/// Serde adapter for quantized fields, use as `#[serde(with = "Quantized::<Position>")]`
+pub struct Quantized<Q>(PhantomData<Q>);
+
+impl<Q: Quantization> Quantized<Q> {
+    pub fn serialize<T: Quantize, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        debug_assert!(
+            Q::bits() * T::AXES <= u64::BITS,
+            "quantized value does not fit in 64 bits"
+        );
+        value.pack::<Q>().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: Quantize, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        u64::deserialize(deserializer).map(T::unpack::<Q>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<Q: Quantization>(value: f32) -> f32 {
+        f32::unpack::<Q>(value.pack::<Q>())
+    }
+
+    #[test]
+    fn bits_per_axis() {
+        assert_eq!(Position::bits(), 16);
+        assert_eq!(Scale::bits(), 11);
+    }
+
+    #[test]
+    fn bounds_round_trip_exactly() {
+        assert_eq!(Position::quantize(Position::MIN), 0);
+        assert_eq!(Position::quantize(Position::MAX), Position::max_step());
+        assert_eq!(round_trip::<Position>(Position::MIN), Position::MIN);
+        assert_eq!(round_trip::<Position>(Position::MAX), Position::MAX);
+        assert_eq!(round_trip::<Scale>(Scale::MIN), Scale::MIN);
+        assert_eq!(round_trip::<Scale>(Scale::MAX), Scale::MAX);
+    }
+
+    #[test]
+    fn values_out_of_bounds_are_clamped() {
+        assert_eq!(round_trip::<Position>(-1e9), Position::MIN);
+        assert_eq!(round_trip::<Position>(1e9), Position::MAX);
+        assert_eq!(round_trip::<Position>(f32::NEG_INFINITY), Position::MIN);
+        assert_eq!(round_trip::<Scale>(-1.), Scale::MIN);
+        assert_eq!(round_trip::<Scale>(17.), Scale::MAX);
+    }
+
+    #[test]
+    fn steps_round_trip_exactly() {
+        for value in [0., 1., -1., 0.125, -0.125, 2047.875, -2047.875, 123.5] {
+            assert_eq!(round_trip::<Position>(value), value);
+        }
+        for value in [1., 1. / 64., 15. + 63. / 64.] {
+            assert_eq!(round_trip::<Scale>(value), value);
+        }
+    }
+
+    #[test]
+    fn values_between_steps_are_off_by_at_most_half_a_step() {
+        // f32 itself is only exact to about 1/4096 near the bounds
+        let tolerance = Position::PRECISION / 2. + Position::MAX * f32::EPSILON;
+        for i in 0..=(4096. / 0.37) as u32 {
+            let value = Position::MIN + i as f32 * 0.37;
+            let error = (round_trip::<Position>(value) - value).abs();
+            assert!(error <= tolerance, "{} is off by {}", value, error);
+        }
+
+        // just under half a step rounds down, just over rounds up
+        let below = 10. + Position::PRECISION * 0.49;
+        let above = 10. + Position::PRECISION * 0.51;
+        assert_eq!(round_trip::<Position>(below), 10.);
+        assert_eq!(round_trip::<Position>(above), 10. + Position::PRECISION);
+    }
+
+    #[test]
+    fn axes_are_packed_separately() {
+        let corners = [
+            Vec2::new(Position::MIN, Position::MAX),
+            Vec2::new(Position::MAX, Position::MIN),
+            Vec2::new(Position::MAX, Position::MAX),
+        ];
+        for value in corners {
+            assert_eq!(Vec2::unpack::<Position>(value.pack::<Position>()), value);
+        }
+
+        let value = Vec3::new(-12.5, 0.125, 2000.);
+        assert_eq!(Vec3::unpack::<Position>(value.pack::<Position>()), value);
+
+        // the packed value fits its axes and nothing more
+        let packed = Vec2::splat(Position::MAX).pack::<Position>();
+        let max_step = Position::max_step();
+        assert_eq!(packed, max_step << Position::bits() | max_step);
+        let packed = Vec2::splat(Scale::MAX).pack::<Scale>();
+        assert!(packed < 1 << (2 * Scale::bits()));
+    }
+}
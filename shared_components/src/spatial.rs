@@ -0,0 +1,362 @@
+//! Spatial hash grid for proximity queries
+//!
+//! The [`SpatialGrid`] buckets entities by the square cell their position falls in, so radius,
+//! rectangle and nearest-neighbour queries only look at the cells around the query instead of
+//! at every entity. [`update_spatial_grid`] keeps it in sync with `Transform` changes; it runs
+//! at the end of the frame, so during a frame the grid has the positions of the previous one.
+//! Callers that need exact positions should pad their queries by how far things move per tick.
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+pub struct SpatialGrid {
+    cell_size: f32,
+    /// Entities and their positions, by cell
+    cells: HashMap<IVec2, Vec<(Entity, Vec2)>>,
+    /// The cell each entity is in
+    entries: HashMap<Entity, IVec2>,
+}
+
+impl Default for SpatialGrid {
+    fn default() -> Self {
+        SpatialGrid::new(64.)
+    }
+}
+
+impl SpatialGrid {
+    /// Queries are fastest with cells about the size of a typical query radius
+    pub fn new(cell_size: f32) -> Self {
+        assert!(cell_size > 0., "cell size must be positive");
+        SpatialGrid {
+            cell_size,
+            cells: HashMap::default(),
+            entries: HashMap::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn position(&self, entity: Entity) -> Option<Vec2> {
+        let cell = self.entries.get(&entity)?;
+        self.cells[cell]
+            .iter()
+            .find(|(e, _)| *e == entity)
+            .map(|(_, position)| *position)
+    }
+
+    fn cell(&self, position: Vec2) -> IVec2 {
+        (position / self.cell_size).floor().as_ivec2()
+    }
+
+    /// Adds an entity, or moves it if it is already in the grid
+    pub fn insert(&mut self, entity: Entity, position: Vec2) {
+        let cell = self.cell(position);
+        match self.entries.insert(entity, cell) {
+            Some(old_cell) if old_cell == cell => {
+                let entities = self.cells.get_mut(&cell).unwrap();
+                if let Some(entry) = entities.iter_mut().find(|(e, _)| *e == entity) {
+                    entry.1 = position;
+                }
+                return;
+            }
+            Some(old_cell) => self.remove_from_cell(entity, old_cell),
+            None => {}
+        }
+        self.cells.entry(cell).or_default().push((entity, position));
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        if let Some(cell) = self.entries.remove(&entity) {
+            self.remove_from_cell(entity, cell);
+        }
+    }
+
+    fn remove_from_cell(&mut self, entity: Entity, cell: IVec2) {
+        if let Some(entities) = self.cells.get_mut(&cell) {
+            if let Some(i) = entities.iter().position(|(e, _)| *e == entity) {
+                entities.swap_remove(i);
+            }
+            if entities.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    /// Entities in the cells overlapping a rectangle, some of them may be outside it
+    fn candidates(&self, min: Vec2, max: Vec2) -> impl Iterator<Item = (Entity, Vec2)> + '_ {
+        let (min_cell, max_cell) = (self.cell(min), self.cell(max));
+        (min_cell.y..=max_cell.y)
+            .flat_map(move |y| (min_cell.x..=max_cell.x).map(move |x| IVec2::new(x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+
+    /// Entities inside the rectangle from `min` to `max`, edges included
+    pub fn within_rect(&self, min: Vec2, max: Vec2) -> impl Iterator<Item = (Entity, Vec2)> + '_ {
+        self.candidates(min, max)
+            .filter(move |(_, position)| position.cmpge(min).all() && position.cmple(max).all())
+    }
+
+    /// Entities at most `radius` from `center`
+    pub fn within_radius(
+        &self,
+        center: Vec2,
+        radius: f32,
+    ) -> impl Iterator<Item = (Entity, Vec2)> + '_ {
+        let radius_squared = radius * radius;
+        self.candidates(center - radius, center + radius)
+            .filter(move |(_, position)| position.distance_squared(center) <= radius_squared)
+    }
+
+    /// The closest entity to `point` that passes `filter`, no further than `max_distance`
+    ///
+    /// Looks at rings of cells around the point, nearest first, and stops once no cell left
+    /// can hold anything closer than what was found.
+    pub fn nearest(
+        &self,
+        point: Vec2,
+        max_distance: f32,
+        mut filter: impl FnMut(Entity) -> bool,
+    ) -> Option<(Entity, f32)> {
+        let center = self.cell(point);
+        let max_ring = (max_distance / self.cell_size).ceil() as i32 + 1;
+        let mut best: Option<(Entity, f32)> = None;
+
+        for ring in 0..=max_ring {
+            // everything in this ring or further out is at least this far away
+            let ring_distance = (ring - 1).max(0) as f32 * self.cell_size;
+            if ring_distance > max_distance || matches!(best, Some((_, d)) if d <= ring_distance) {
+                break;
+            }
+
+            for cell in ring_cells(center, ring) {
+                let entities = match self.cells.get(&cell) {
+                    Some(entities) => entities,
+                    None => continue,
+                };
+                for (entity, position) in entities {
+                    let distance = position.distance(point);
+                    let closer = match best {
+                        Some((_, best_distance)) => distance < best_distance,
+                        None => distance <= max_distance,
+                    };
+                    if closer && filter(*entity) {
+                        best = Some((*entity, distance));
+                    }
+                }
+            }
+        }
+        best
+    }
+}
+
+/// The cells on the border of the square `ring` cells out from `center`
+fn ring_cells(center: IVec2, ring: i32) -> impl Iterator<Item = IVec2> {
+    (-ring..=ring).flat_map(move |y| {
+        // only the two ends of rows between the top and bottom are on the border
+        let step = if y.abs() == ring {
+            1
+        } else {
+            (2 * ring).max(1)
+        };
+        (-ring..=ring)
+            .step_by(step as usize)
+            .map(move |x| center + IVec2::new(x, y))
+    })
+}
+
+/// Moves changed entities in the grid and drops ones that lost their `Transform`
+pub fn update_spatial_grid(
+    mut grid: ResMut<SpatialGrid>,
+    moved: Query<(Entity, &Transform), Changed<Transform>>,
+    removed: RemovedComponents<Transform>,
+) {
+    for entity in removed.iter() {
+        grid.remove(entity);
+    }
+    for (entity, transform) in moved.iter() {
+        grid.insert(entity, transform.translation.truncate());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const CELL_SIZE: f32 = 10.;
+
+    /// Random points around the origin, and points right on cell edges and corners
+    fn points() -> Vec<(Entity, Vec2)> {
+        let mut rng = StdRng::seed_from_u64(7);
+        let random =
+            (0..300).map(|_| Vec2::new(rng.gen_range(-55.0..55.), rng.gen_range(-55.0..55.)));
+        let edges = (-3..=3).flat_map(|y| {
+            (-3..=3).map(move |x| Vec2::new(x as f32, y as f32) * CELL_SIZE + Vec2::new(0., 0.5))
+        });
+        let corners =
+            (-3..=3).flat_map(|y| (-3..=3).map(move |x| Vec2::new(x as f32, y as f32) * CELL_SIZE));
+        random
+            .chain(edges)
+            .chain(corners)
+            .enumerate()
+            .map(|(i, position)| (Entity::from_raw(i as u32), position))
+            .collect()
+    }
+
+    fn grid(points: &[(Entity, Vec2)]) -> SpatialGrid {
+        let mut grid = SpatialGrid::new(CELL_SIZE);
+        for (entity, position) in points {
+            grid.insert(*entity, *position);
+        }
+        grid
+    }
+
+    /// Query points: random ones, cell corners and points on cell edges
+    fn queries() -> Vec<Vec2> {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut queries: Vec<Vec2> = (0..50)
+            .map(|_| Vec2::new(rng.gen_range(-70.0..70.), rng.gen_range(-70.0..70.)))
+            .collect();
+        queries.extend([
+            Vec2::ZERO,
+            Vec2::new(-10., -10.),
+            Vec2::new(-20., 30.),
+            Vec2::new(-10., 4.),
+            Vec2::new(25., -30.),
+        ]);
+        queries
+    }
+
+    fn sorted(entities: impl Iterator<Item = (Entity, Vec2)>) -> Vec<Entity> {
+        let mut entities: Vec<Entity> = entities.map(|(entity, _)| entity).collect();
+        entities.sort();
+        entities
+    }
+
+    #[test]
+    fn within_radius_matches_a_full_scan() {
+        let points = points();
+        let grid = grid(&points);
+
+        for center in queries() {
+            for radius in [0., 0.5, 10., 14.2, 25., 80.] {
+                let expected = sorted(
+                    points
+                        .iter()
+                        .copied()
+                        .filter(|(_, p)| p.distance_squared(center) <= radius * radius),
+                );
+                assert_eq!(
+                    sorted(grid.within_radius(center, radius)),
+                    expected,
+                    "radius {} around {}",
+                    radius,
+                    center
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn within_rect_matches_a_full_scan() {
+        let points = points();
+        let grid = grid(&points);
+
+        for min in queries() {
+            for size in [
+                Vec2::ZERO,
+                Vec2::new(10., 10.),
+                Vec2::new(0.5, 30.),
+                Vec2::splat(70.),
+            ] {
+                let max = min + size;
+                let expected =
+                    sorted(points.iter().copied().filter(|(_, p)| {
+                        p.x >= min.x && p.y >= min.y && p.x <= max.x && p.y <= max.y
+                    }));
+                assert_eq!(
+                    sorted(grid.within_rect(min, max)),
+                    expected,
+                    "rect from {} to {}",
+                    min,
+                    max
+                );
+            }
+        }
+    }
+
+    /// Distance to the closest point that passes the filter, if any is close enough
+    fn nearest_distance(
+        points: &[(Entity, Vec2)],
+        point: Vec2,
+        max_distance: f32,
+        filter: impl Fn(Entity) -> bool,
+    ) -> Option<f32> {
+        points
+            .iter()
+            .filter(|(entity, _)| filter(*entity))
+            .map(|(_, p)| p.distance(point))
+            .filter(|distance| *distance <= max_distance)
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    fn check_nearest(filter: impl Fn(Entity) -> bool + Copy) {
+        let points = points();
+        let grid = grid(&points);
+
+        for point in queries() {
+            for max_distance in [0., 3., 10., 25., 200.] {
+                let expected = nearest_distance(&points, point, max_distance, filter);
+                let found = grid.nearest(point, max_distance, filter);
+                // ties may pick either entity, but the distance has to be the closest one
+                assert_eq!(
+                    found.map(|(_, distance)| distance),
+                    expected,
+                    "nearest to {} within {}",
+                    point,
+                    max_distance
+                );
+                if let Some((entity, distance)) = found {
+                    assert!(filter(entity));
+                    assert_eq!(grid.position(entity).unwrap().distance(point), distance);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_matches_a_full_scan() {
+        check_nearest(|_| true);
+    }
+
+    #[test]
+    fn nearest_with_a_filter_matches_a_full_scan() {
+        check_nearest(|entity| entity.id() % 3 == 0);
+        check_nearest(|_| false);
+    }
+
+    #[test]
+    fn moved_and_removed_entities_are_found_where_they_are() {
+        let mut grid = SpatialGrid::new(CELL_SIZE);
+        let entity = Entity::from_raw(0);
+        grid.insert(entity, Vec2::new(-5., -5.));
+        grid.insert(entity, Vec2::new(-5., -4.));
+        grid.insert(entity, Vec2::new(25., 0.));
+        assert_eq!(grid.len(), 1);
+        assert_eq!(grid.position(entity), Some(Vec2::new(25., 0.)));
+        assert_eq!(sorted(grid.within_radius(Vec2::new(-5., -5.), 5.)), vec![]);
+
+        grid.remove(entity);
+        assert!(grid.is_empty());
+        assert_eq!(grid.nearest(Vec2::ZERO, 100., |_| true), None);
+    }
+}
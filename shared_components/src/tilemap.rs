@@ -0,0 +1,186 @@
+//! The tile grid the world is made of
+//!
+//! A [`Tilemap`] covers the world from its top left corner, centered on the origin. Every
+//! [`Tile`] has a sprite from the tileset, a layer it is drawn on and whether it is solid.
+//! Solid tiles block movement like obstacles do, see [`crate::collision`] and
+//! [`crate::navigation`].
+//!
+//! Maps are `.tilemap.json` files that draw the map as rows of characters, with a legend that
+//! says which tile each character is:
+//!
+//! ```json
+//! {
+//!   "tileset": "tiles.png",
+//!   "tileset_columns": 4,
+//!   "tile_size": 24,
+//!   "legend": {
+//!     ".": {"sprite": 0, "layer": 0, "solid": false},
+//!     "#": {"sprite": 2, "layer": 1, "solid": true}
+//!   },
+//!   "rows": ["###", "#.#", "###"]
+//! }
+//! ```
+//!
+//! The server loads the map with [`TilemapAssetPlugin`] and replicates it as a resource, so
+//! clients get it with their join snapshot.
+
+use crate::Tilemap;
+use bevy::asset::{AssetLoader, BoxedFuture, Error, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::{FromReflect, TypeUuid, Uuid};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, Reflect, FromReflect)]
+pub struct Tile {
+    /// Index of the sprite in the tileset
+    pub sprite: u32,
+    /// Tiles on higher layers are drawn over lower ones
+    pub layer: u8,
+    /// Nothing can walk through solid tiles
+    pub solid: bool,
+}
+
+impl TypeUuid for Tilemap {
+    const TYPE_UUID: Uuid = Uuid::from_u128(0x3c1d_7a52_94e8_4f0b_b6a1_2e5c_d8f4_0a97);
+}
+
+impl Tilemap {
+    /// Size of the world the map covers
+    pub fn size(&self) -> Vec2 {
+        Vec2::new(self.width as f32, self.height as f32) * self.tile_size
+    }
+
+    pub fn tile(&self, x: u32, y: u32) -> Option<&Tile> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.tiles.get((y * self.width + x) as usize)
+    }
+
+    /// Every tile with its column and row
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32, &Tile)> {
+        self.tiles
+            .iter()
+            .enumerate()
+            .map(|(i, tile)| (i as u32 % self.width, i as u32 / self.width, tile))
+    }
+
+    /// World position of the center of a tile
+    pub fn tile_center(&self, x: u32, y: u32) -> Vec2 {
+        let (min, max) = self.tile_rect(x, y);
+        (min + max) / 2.
+    }
+
+    /// Corners of a tile in the world, the bottom left one first
+    pub fn tile_rect(&self, x: u32, y: u32) -> (Vec2, Vec2) {
+        let top_left = self.size() * Vec2::new(-0.5, 0.5);
+        let min = top_left + Vec2::new(x as f32, -(y as f32 + 1.)) * self.tile_size;
+        (min, min + self.tile_size)
+    }
+
+    /// Rectangles of the solid tiles that overlap the rectangle from `min` to `max`
+    pub fn solid_rects(&self, min: Vec2, max: Vec2) -> impl Iterator<Item = (Vec2, Vec2)> + '_ {
+        let top_left = self.size() * Vec2::new(-0.5, 0.5);
+        // columns grow to the right, rows downwards
+        let first = ((Vec2::new(min.x, max.y) - top_left) * Vec2::new(1., -1.) / self.tile_size)
+            .floor()
+            .max(Vec2::ZERO);
+        let last = ((Vec2::new(max.x, min.y) - top_left) * Vec2::new(1., -1.) / self.tile_size)
+            .floor()
+            .min(Vec2::new(self.width as f32, self.height as f32) - 1.);
+        let (first, last) = (first.as_ivec2(), last.as_ivec2());
+
+        (first.y..=last.y)
+            .flat_map(move |y| (first.x..=last.x).map(move |x| (x as u32, y as u32)))
+            .filter(|(x, y)| self.tile(*x, *y).map_or(false, |tile| tile.solid))
+            .map(|(x, y)| self.tile_rect(x, y))
+    }
+
+    /// Whether a circle at `position` stays clear of every solid tile
+    pub fn is_clear(&self, position: Vec2, radius: f32) -> bool {
+        self.solid_rects(position - radius, position + radius)
+            .all(|(min, max)| {
+                position.clamp(min, max).distance_squared(position) >= radius * radius
+            })
+    }
+
+    /// Rectangles of every solid tile
+    pub fn all_solid_rects(&self) -> impl Iterator<Item = (Vec2, Vec2)> + '_ {
+        self.iter()
+            .filter(|(_, _, tile)| tile.solid)
+            .map(|(x, y, _)| self.tile_rect(x, y))
+    }
+}
+
+/// A map as it is written in `.tilemap.json` files
+#[derive(Deserialize)]
+struct TilemapFile {
+    tileset: String,
+    tileset_columns: u32,
+    tile_size: f32,
+    legend: HashMap<char, Tile>,
+    rows: Vec<String>,
+}
+
+impl TryFrom<TilemapFile> for Tilemap {
+    type Error = Error;
+
+    fn try_from(file: TilemapFile) -> Result<Self, Error> {
+        let width = file.rows.first().map_or(0, |row| row.chars().count());
+        let mut tiles = Vec::with_capacity(width * file.rows.len());
+
+        for (y, row) in file.rows.iter().enumerate() {
+            if row.chars().count() != width {
+                anyhow::bail!("row {} is not {} tiles wide", y, width);
+            }
+            for (x, c) in row.chars().enumerate() {
+                match file.legend.get(&c) {
+                    Some(tile) => tiles.push(*tile),
+                    None => anyhow::bail!("tile '{}' at {}, {} is not in the legend", c, x, y),
+                }
+            }
+        }
+
+        Ok(Tilemap {
+            tileset: file.tileset,
+            tileset_columns: file.tileset_columns,
+            tile_size: file.tile_size,
+            width: width as u32,
+            height: file.rows.len() as u32,
+            tiles,
+        })
+    }
+}
+
+#[derive(Default)]
+struct TilemapLoader;
+
+impl AssetLoader for TilemapLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, anyhow::Result<(), Error>> {
+        Box::pin(async move {
+            let file: TilemapFile = serde_json::from_slice(bytes)?;
+            let tilemap = Tilemap::try_from(file)?;
+            load_context.set_default_asset(LoadedAsset::new(tilemap));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tilemap.json"]
+    }
+}
+
+/// Loads tilemaps, without their tileset
+pub struct TilemapAssetPlugin;
+
+impl Plugin for TilemapAssetPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Tilemap>()
+            .init_asset_loader::<TilemapLoader>();
+    }
+}